@@ -0,0 +1,225 @@
+//! A minimal DHT (BEP 5) client, for finding peers when the tracker can't help.
+//!
+//! We only ever look peers up: a lookup sends `get_peers` queries to the nodes closest to the info
+//! hash that we know of, which answer with peers or with nodes that are closer still. While a
+//! lookup runs we also answer `ping`s, so other nodes don't write us off. We don't keep a routing
+//! table between lookups, and we don't announce ourselves.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_bencode::value::Value;
+use sha1::Digest;
+use tokio::net::UdpSocket;
+use tracing::{debug, instrument, trace};
+
+/// Well-known nodes that every lookup starts from.
+const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// How many of the closest nodes we query at once.
+const ALPHA: usize = 8;
+
+/// How long we wait for a round of queries to be answered.
+const ROUND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many rounds of queries a lookup runs before giving up.
+const MAX_ROUNDS: usize = 10;
+
+/// A lookup stops once it has found this many peers.
+const ENOUGH_PEERS: usize = 50;
+
+type NodeId = [u8; 20];
+
+/// Asks the DHT for peers that have the torrent with the given info hash.
+///
+/// Returns whatever peers were found (possibly none) once the lookup runs out of closer nodes to
+/// ask, or out of rounds.
+#[instrument(skip_all, fields(info_hash = hex::encode(info_hash)))]
+pub(crate) async fn get_peers(info_hash: [u8; 20]) -> anyhow::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("bind DHT socket")?;
+    let our_id: NodeId = sha1::Sha1::digest(crate::PEER_ID).into();
+
+    let mut bootstrap = Vec::new();
+    for node in BOOTSTRAP_NODES {
+        match tokio::net::lookup_host(node).await {
+            Ok(addrs) => bootstrap.extend(addrs.filter(SocketAddr::is_ipv4)),
+            Err(e) => debug!("failed to resolve DHT bootstrap node {node}: {e}"),
+        }
+    }
+    anyhow::ensure!(
+        !bootstrap.is_empty(),
+        "could not resolve any DHT bootstrap node"
+    );
+
+    // nodes we've heard of but not queried yet, keyed by their distance to the info hash so the
+    // closest come first
+    let mut candidates: BTreeMap<NodeId, SocketAddr> = BTreeMap::new();
+    let mut queried = HashSet::new();
+    let mut peers = Vec::new();
+    let mut seen_peers = HashSet::new();
+    let mut next_tid = 0u16;
+    let mut buf = vec![0; 1 << 16];
+
+    for round in 0..MAX_ROUNDS {
+        let targets: Vec<SocketAddr> = if round == 0 {
+            bootstrap.clone()
+        } else {
+            std::iter::from_fn(|| candidates.pop_first().map(|(_, addr)| addr))
+                .take(ALPHA)
+                .collect()
+        };
+        if targets.is_empty() {
+            break;
+        }
+
+        // transaction id -> the node we asked
+        let mut outstanding = HashMap::new();
+        for addr in targets {
+            if !queried.insert(addr) {
+                continue;
+            }
+            let tid = next_tid.to_be_bytes().to_vec();
+            next_tid = next_tid.wrapping_add(1);
+            let query = dict([
+                ("t", Value::Bytes(tid.clone())),
+                ("y", Value::Bytes(b"q".to_vec())),
+                ("q", Value::Bytes(b"get_peers".to_vec())),
+                (
+                    "a",
+                    dict([
+                        ("id", Value::Bytes(our_id.to_vec())),
+                        ("info_hash", Value::Bytes(info_hash.to_vec())),
+                    ]),
+                ),
+            ]);
+            let query = serde_bencode::to_bytes(&query).context("encode get_peers query")?;
+            if let Err(e) = socket.send_to(&query, addr).await {
+                debug!(node = %addr, "failed to send get_peers: {e}");
+                continue;
+            }
+            outstanding.insert(tid, addr);
+        }
+
+        let deadline = tokio::time::Instant::now() + ROUND_TIMEOUT;
+        while !outstanding.is_empty() {
+            let (n, from) =
+                match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    Err(_) => break,
+                    Ok(Err(e)) => {
+                        debug!("failed to receive from DHT socket: {e}");
+                        continue;
+                    }
+                    Ok(Ok(received)) => received,
+                };
+            let Ok(Value::Dict(msg)) = crate::from_bencode::<Value>(&buf[..n]) else {
+                trace!(node = %from, "ignoring malformed DHT message");
+                continue;
+            };
+            let Some(Value::Bytes(tid)) = msg.get(b"t".as_slice()) else {
+                continue;
+            };
+            match msg.get(b"y".as_slice()) {
+                Some(Value::Bytes(y)) if y == b"q" => {
+                    if let Some(Value::Bytes(q)) = msg.get(b"q".as_slice()) {
+                        if q == b"ping" {
+                            let pong = dict([
+                                ("t", Value::Bytes(tid.clone())),
+                                ("y", Value::Bytes(b"r".to_vec())),
+                                ("r", dict([("id", Value::Bytes(our_id.to_vec()))])),
+                            ]);
+                            let pong = serde_bencode::to_bytes(&pong).context("encode pong")?;
+                            let _ = socket.send_to(&pong, from).await;
+                        }
+                    }
+                    continue;
+                }
+                Some(Value::Bytes(y)) if y == b"r" => {}
+                _ => {
+                    // an error, or something we don't understand; either way the node is no help
+                    if outstanding.get(tid) == Some(&from) {
+                        outstanding.remove(tid);
+                    }
+                    continue;
+                }
+            }
+            if outstanding.get(tid) != Some(&from) {
+                trace!(node = %from, "ignoring unsolicited DHT response");
+                continue;
+            }
+            outstanding.remove(tid);
+            let Some(Value::Dict(response)) = msg.get(b"r".as_slice()) else {
+                continue;
+            };
+
+            if let Some(Value::List(values)) = response.get(b"values".as_slice()) {
+                for value in values {
+                    let Value::Bytes(peer) = value else {
+                        continue;
+                    };
+                    if let Some(peer) = compact_addr(peer) {
+                        if seen_peers.insert(peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+            }
+            if let Some(Value::Bytes(nodes)) = response.get(b"nodes".as_slice()) {
+                for node in nodes.chunks_exact(26) {
+                    let (id, addr) = node.split_at(20);
+                    let Some(addr) = compact_addr(addr) else {
+                        continue;
+                    };
+                    if !queried.contains(&addr) {
+                        let mut distance = [0; 20];
+                        for (d, (a, b)) in distance.iter_mut().zip(id.iter().zip(&info_hash)) {
+                            *d = a ^ b;
+                        }
+                        candidates.insert(distance, addr);
+                    }
+                }
+            }
+        }
+
+        debug!(round, peers = peers.len(), "DHT lookup round finished");
+        if peers.len() >= ENOUGH_PEERS {
+            break;
+        }
+    }
+
+    debug!(
+        peers = peers.len(),
+        nodes = queried.len(),
+        "DHT lookup finished"
+    );
+    Ok(peers)
+}
+
+/// Parses a 6-byte compact IPv4 address and port.
+pub(crate) fn compact_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let &[a, b, c, d, p1, p2] = bytes else {
+        return None;
+    };
+    Some(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::new(a, b, c, d),
+        u16::from_be_bytes([p1, p2]),
+    )))
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
@@ -1,19 +1,53 @@
-use std::collections::BinaryHeap;
+//! Downloading a whole torrent.
+//!
+//! The work is split between:
+//!
+//! - this module, which finds peers (through the tracker, the DHT, PEX and incoming
+//!   connections), keeps up to [`Options::max_peers`] of them downloading, and verifies and
+//!   stores every piece they hand back;
+//! - the `Scheduler` (in `scheduler.rs`), which decides which piece each peer downloads next,
+//!   rarest first;
+//! - each [`Peer`], which requests the blocks of the piece it's working on itself;
+//! - the torrent's web seeds (in `webseed.rs`), which download pieces over HTTP when there aren't
+//!   enough peers;
+//! - a [`Storage`], which holds the finished pieces.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use futures_util::StreamExt;
-use sha1::{Digest, Sha1};
+use sha1::Digest;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
 use crate::{
-    peer::Peer,
+    dht,
+    peer::{self, Bitfield, Encryption, Peer},
+    pex,
     piece::Piece,
+    scheduler::Scheduler,
+    storage::{MemoryStorage, Storage},
     torrent::{File, Keys, Torrent},
-    tracker::TrackerResponse,
-    BLOCK_MAX,
+    tracker::{self, Event, TrackerRequest, TrackerResponse},
+    webseed::{self, WebSeed},
+    Error,
 };
 
 pub struct Downloaded {
-    bytes: Vec<u8>, // TODO: maybe Bytes?
+    bytes: Vec<u8>,
     files: Vec<File>,
 }
 
@@ -25,6 +59,39 @@ impl<'a> IntoIterator for &'a Downloaded {
     }
 }
 
+impl Downloaded {
+    /// Every file in the download, with its path relative to the torrent's root (just the file
+    /// name for single-file torrents).
+    ///
+    /// The paths come straight from the torrent, so check them (e.g. for `..`) before writing
+    /// anywhere.
+    pub fn iter_files(&self) -> impl Iterator<Item = (PathBuf, &[u8])> {
+        self.into_iter()
+            .map(|file| (file.path().iter().collect(), file.bytes()))
+    }
+
+    /// Re-checks every piece of the assembled download against the torrent's piece hashes.
+    ///
+    /// Each piece is already verified as it arrives, so this only catches bugs in how the pieces
+    /// were stitched together.
+    pub fn verify_all(&self, t: &Torrent) -> Result<(), Error> {
+        if self.bytes.len() != t.length() {
+            return Err(Error::Other(anyhow::anyhow!(
+                "downloaded {} bytes, expected {}",
+                self.bytes.len(),
+                t.length()
+            )));
+        }
+        for piece_i in 0..t.info.num_pieces() {
+            let piece = &self.bytes[piece_i * t.info.plength..][..t.info.piece_size(piece_i)];
+            if !t.info.verify_piece(piece_i, piece) {
+                return Err(Error::PieceHashMismatch { piece: piece_i });
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct DownloadedIter<'d> {
     downloaded: &'d Downloaded,
     file_iter: std::slice::Iter<'d, File>,
@@ -67,166 +134,924 @@ impl<'d> DownloadedFile<'d> {
     }
 }
 
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
+/// What happened during a download, for a summary at the end.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadStats {
+    /// How many bytes of the torrent we downloaded and stored.
+    pub bytes: usize,
+    /// How long the whole download took, from asking the tracker for peers to the last piece.
+    pub elapsed: Duration,
+    /// The bytes of stored pieces that each peer sent us; peers that never completed a piece
+    /// aren't in here.
+    pub bytes_per_peer: BTreeMap<SocketAddr, usize>,
+    /// The bytes of stored pieces that each web seed sent us, by URL.
+    pub bytes_per_web_seed: BTreeMap<String, usize>,
+    /// The pieces that had to be downloaded again at least once, because they failed hash
+    /// verification or a peer gave up on them half-way.
+    pub retried_pieces: BTreeSet<usize>,
+}
+
+impl DownloadStats {
+    /// How many peers sent us at least one of the pieces we stored.
+    pub fn peers_used(&self) -> usize {
+        self.bytes_per_peer.len()
+    }
+
+    /// The average download speed over the whole download, in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// How many pieces that fail hash verification we accept from a peer before we drop it.
+const MAX_BAD_PIECES: usize = 3;
+
+/// How many reports (and so finished pieces) per peer may wait for us to store them.
+///
+/// Along with the one piece each participant may be working on, this caps how many pieces are
+/// in memory at once.
+const REPORTS_PER_PEER: usize = 2;
+
+/// Knobs for [`Torrent::download_all`].
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The port to accept peer connections on (falls back to 6881-6889 if taken).
+    pub port: u16,
+    /// How many block requests to keep outstanding with each peer.
+    ///
+    /// More hides more of the round-trip time to each peer, at the cost of more wasted requests
+    /// when a peer chokes us.
+    pub pipeline_depth: usize,
+    /// How many bytes to ask for in each block request.
+    ///
+    /// The default of [`BLOCK_MAX`](crate::BLOCK_MAX) is what every peer accepts; many refuse
+    /// anything bigger, but on a LAN between our own clients bigger blocks mean fewer requests.
+    /// It can be at most [`MAX_BLOCK_SIZE`](crate::peer::MAX_BLOCK_SIZE).
+    pub block_size: usize,
+    /// How long a peer may leave all our block requests unanswered before we give up on it, and
+    /// hand its piece to another peer.
+    pub block_timeout: Duration,
+    /// The most peers to download from at once.
+    ///
+    /// Peers beyond this wait in a pool, and replace those that drop out; the ones that have the
+    /// most of the pieces we still need go first. We also stop connecting to new peers while the
+    /// pool is full. There's no limit on the download rate itself, so this is what to turn down
+    /// to use less bandwidth (and fewer sockets).
+    pub max_peers: usize,
+    /// Whether to check every piece against its hash before accepting it.
+    ///
+    /// Turning this off is unsafe: a single bad peer can then corrupt the download.
+    pub verify: bool,
+    /// How many downloaded pieces to hash at once, each on its own thread off the async runtime.
+    ///
+    /// Defaults to the number of CPUs, so that hashing keeps up with a fast connection.
+    pub verify_threads: usize,
+    /// How many times a piece may fail hash verification before we give up on the download.
+    ///
+    /// If every peer serves the same corrupt piece, retrying it forever gets us nowhere.
+    pub max_piece_attempts: usize,
+    /// Whether to encrypt our connections to peers (and accept encrypted ones from them).
+    pub encryption: Encryption,
+    /// Peers to download from instead of asking the tracker (or the DHT) for some.
+    pub peers: Vec<SocketAddr>,
+    /// How many peers to ask the tracker for on each announce, if not its default.
+    pub numwant: Option<usize>,
+    /// The proxy to send tracker and web seed requests through (e.g. `http://proxy:3128`), rather
+    /// than whatever the `HTTP_PROXY` and `HTTPS_PROXY` environment variables say.
+    pub proxy: Option<String>,
+    /// Only download these pieces, by index, rather than the whole torrent.
+    ///
+    /// The rest of the storage is left as it is, so this can repair a few bad pieces of an
+    /// otherwise complete download.
+    pub pieces: Option<Vec<usize>>,
+    /// Whether the storage may already hold some of the torrent, e.g. from an interrupted
+    /// download.
+    ///
+    /// Every piece in it is hashed before we start, and only the ones that don't match are
+    /// downloaded; the files may have changed since, so nothing is taken on trust.
+    pub resume: bool,
+    /// Like [`resume`](Self::resume), but taking the storage's own record of which pieces are
+    /// complete (see [`Storage::completed`]) on trust, rather than hashing every piece. Without
+    /// a record that's still good, every piece is hashed after all.
+    pub fast_resume: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            pipeline_depth: 5,
+            block_size: crate::BLOCK_MAX,
+            block_timeout: crate::peer::BLOCK_TIMEOUT,
+            max_peers: 30,
+            verify: true,
+            verify_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_piece_attempts: 5,
+            encryption: Encryption::Disable,
+            peers: Vec::new(),
+            numwant: None,
+            proxy: None,
+            pieces: None,
+            resume: false,
+            fast_resume: false,
+        }
+    }
+}
+
+pub(crate) async fn all(
+    t: &Torrent,
+    options: &Options,
+    cancel: CancellationToken,
+) -> Result<(Downloaded, DownloadStats), Error> {
+    let mut storage = MemoryStorage::new(&t.info);
+    let stats = download(t, options, &mut storage, cancel).await?;
+    let downloaded = Downloaded {
+        bytes: storage.into_bytes(),
+        files: files(t),
+    };
+    Ok((downloaded, stats))
+}
+
+pub(crate) async fn into(
+    t: &Torrent,
+    options: &Options,
+    storage: &mut impl Storage,
+    cancel: CancellationToken,
+) -> Result<DownloadStats, Error> {
+    Ok(download(t, options, storage, cancel).await?)
+}
+
+async fn download(
+    t: &Torrent,
+    options: &Options,
+    storage: &mut impl Storage,
+    cancel: CancellationToken,
+) -> anyhow::Result<DownloadStats> {
+    let started = Instant::now();
+    let mut stats = DownloadStats::default();
+    let num_pieces = t.info.num_pieces();
+    anyhow::ensure!(options.max_peers > 0, "need to allow at least one peer");
+    anyhow::ensure!(
+        (1..=crate::peer::MAX_BLOCK_SIZE).contains(&options.block_size),
+        "block size must be from 1 to {} bytes, not {}",
+        crate::peer::MAX_BLOCK_SIZE,
+        options.block_size
+    );
+    anyhow::ensure!(
+        options.verify_threads > 0,
+        "need at least one thread to verify pieces on"
+    );
+    anyhow::ensure!(
+        options.max_piece_attempts > 0,
+        "need to allow at least one attempt per piece"
+    );
+    if let Some(&piece_i) = options.pieces.iter().flatten().find(|&&i| i >= num_pieces) {
+        anyhow::bail!("piece {piece_i} is out of range; the torrent has {num_pieces} pieces");
+    }
+    let recorded = options.fast_resume.then(|| storage.completed()).flatten();
+    let mut have = if let Some(recorded) = recorded {
+        info!(
+            have = recorded.len(),
+            of = num_pieces,
+            "trusting the record of the pieces we already have"
+        );
+        let mut have = Bitfield::empty(num_pieces);
+        for piece_i in recorded.into_iter().filter(|&i| i < num_pieces) {
+            have.set_piece(piece_i);
+        }
+        have
+    } else if options.resume || options.fast_resume {
+        verify_on_disk(t, storage)
+    } else {
+        Bitfield::empty(num_pieces)
+    };
+    // the pieces we weren't asked for (and don't have) count as done, so we stop once the others
+    // are
+    let mut skipped = HashSet::new();
+    if let Some(pieces) = &options.pieces {
+        for piece_i in 0..num_pieces {
+            if !pieces.contains(&piece_i) && !have.has_piece(piece_i) {
+                have.set_piece(piece_i);
+                skipped.insert(piece_i);
+            }
+        }
+    }
+    if have.is_complete(num_pieces) {
+        // we already have every piece there is to have, so there's no reason to bother the
+        // tracker or any peers (and no work for the dispatch loop to wait on).
+        stats.elapsed = started.elapsed();
+        return Ok(stats);
+    }
+
+    let result = transfer(t, options, storage, &mut have, &mut stats, cancel).await;
+    // however the download ended, the pieces we got are worth remembering
+    let complete: Vec<_> = have
+        .pieces(num_pieces)
+        .filter(|piece_i| !skipped.contains(piece_i))
+        .collect();
+    if let Err(e) = storage.record_completed(&complete) {
+        warn!("failed to record which pieces are complete: {e:#}");
+    }
+    result?;
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Downloads every piece that isn't in `have` into `storage`, adding each to `have` as it's
+/// stored.
+async fn transfer(
+    t: &Torrent,
+    options: &Options,
+    storage: &mut impl Storage,
+    have: &mut Bitfield,
+    stats: &mut DownloadStats,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    let num_pieces = t.info.num_pieces();
     let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
+    let client = tracker::client(options.proxy.as_deref())?;
+    let web_seeds: Vec<_> = t
+        .url_list
+        .iter()
+        .flatten()
+        .filter_map(|url| match WebSeed::new(url, client.clone()) {
+            Ok(seed) => Some(seed),
+            Err(e) => {
+                warn!("ignoring web seed: {e:#}");
+                None
+            }
+        })
+        .collect();
+
+    let listener = peer::listen(options.port).await?;
+    let port = listener
+        .local_addr()
+        .context("get listening address")?
+        .port();
+    let (new_peers, mut incoming) = mpsc::channel(5);
+    // dropping the JoinSet (whenever we return) stops accepting connections
+    let mut background = tokio::task::JoinSet::new();
+    background.spawn(accept(
+        listener,
+        info_hash,
+        t.info.num_pieces(),
+        options.encryption,
+        new_peers.clone(),
+    ));
+
+    let not_needed: usize = have.pieces(num_pieces).map(|i| t.info.piece_size(i)).sum();
+    let mut announce = TrackerRequest {
+        left: t.length() - not_needed,
+        numwant: options.numwant,
+        ..TrackerRequest::new(t, port)
+    };
+    let mut reannounce_after = TrackerResponse::DEFAULT_INTERVAL;
+    // peers we were told to use replace the tracker (and the DHT) altogether, and a torrent we
+    // only had the info hash of has no tracker
+    let use_tracker = options.peers.is_empty() && !t.announce.is_empty();
+    let mut peer_addrs = options.peers.clone();
+    if use_tracker {
+        match TrackerResponse::query(&client, t, info_hash, &announce).await {
+            Ok(peer_info) => {
+                announce.remember(&peer_info);
+                reannounce_after = peer_info.reannounce_after();
+                peer_addrs.extend(peer_info.peer_addrs());
+            }
+            Err(e) if !t.is_private() => warn!(
+                "failed to query tracker for peer info: {:#}",
+                anyhow::Error::from(e)
+            ),
+            Err(e) => return Err(anyhow::Error::from(e).context("query tracker for peer info")),
+        }
+    }
+    // private torrents may only get their peers from the tracker
+    if peer_addrs.is_empty() && !t.is_private() {
+        info!("tracker had no peers for us, so asking the DHT");
+        match dht::get_peers(info_hash).await {
+            Ok(found) => peer_addrs = found,
+            Err(e) if !web_seeds.is_empty() => warn!("failed to look up peers in the DHT: {e:#}"),
+            Err(e) => return Err(e.context("look up peers in the DHT")),
+        }
+    }
+    if peer_addrs.is_empty() {
+        anyhow::ensure!(
+            !web_seeds.is_empty(),
+            if t.is_private() {
+                "tracker returned no peers"
+            } else {
+                "neither the tracker nor the DHT knew of any peers"
+            }
+        );
+        info!("no peers to be found, so downloading from web seeds only");
+    }
 
     let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
+    // we only need a few peers to get going; the rest join through `connect` below
+    let initial_peers = options.max_peers.min(5);
+    // the addresses we've heard back from, one way or the other
+    let mut tried = HashSet::new();
+    // an IPv6 peer we can't reach (e.g. because we have no IPv6 connectivity) just fails to
+    // connect, and we carry on with the IPv4 ones
+    let mut peers = futures_util::stream::iter(peer_addrs.iter().copied())
+        .map(|peer_addr| async move {
+            let num_pieces = t.info.num_pieces();
+            let peer = Peer::new(peer_addr, info_hash, num_pieces, options.encryption).await;
             (peer_addr, peer)
         })
-        .buffer_unordered(5 /* user config */);
+        .buffer_unordered(initial_peers);
     while let Some((peer_addr, peer)) = peers.next().await {
+        tried.insert(peer_addr);
         match peer {
             Ok(peer) => {
                 peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
+                if peer_list.len() >= initial_peers {
                     break;
                 }
             }
             Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+                warn!(peer = %peer_addr, "failed to connect to peer: {e:#}");
             }
         }
     }
     drop(peers);
-    let mut peers = peer_list;
-
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
-        }
-    }
-
-    // TODO
-    assert!(no_peers.is_empty());
-
-    // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
-    // should probably write every piece to disk so that we can also resume downloads, and seed
-    // later on.
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
-        let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
-
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
+    anyhow::ensure!(
+        !peer_list.is_empty() || !web_seeds.is_empty(),
+        "could not connect to any peers"
+    );
+    let peers = peer_list;
+
+    // every peer address we hear of from here on (through the tracker or PEX) ends up here
+    let (discovered, addrs) = mpsc::unbounded_channel();
+    // the peers we haven't got to (or gave up on half-way) are first in line
+    for &peer_addr in &peer_addrs {
+        if !tried.contains(&peer_addr) {
+            let _ = discovered.send(peer_addr);
+        }
+    }
+    background.spawn(connect(
+        addrs,
+        tried,
+        info_hash,
+        t.info.num_pieces(),
+        options.max_peers,
+        options.encryption,
+        new_peers,
+    ));
+    // private torrents may only get their peers from the tracker
+    let swarm = (!t.is_private()).then(|| pex::Swarm::new(discovered.clone()));
+
+    // keep our entry in the tracker alive, and pick up any peers that have joined since
+    let downloaded = Arc::new(AtomicUsize::new(0));
+    if use_tracker {
+        background.spawn(reannounce(
+            client.clone(),
+            t.clone(),
+            announce.clone(),
+            reannounce_after,
+            Arc::clone(&downloaded),
+            discovered,
+        ));
+    }
+
+    let mut need_pieces = Vec::new();
+    for piece_i in (0..num_pieces).filter(|&piece_i| !have.has_piece(piece_i)) {
+        // peers can tell us about new pieces later on, but it's not worth waiting around to see
+        anyhow::ensure!(
+            peers.iter().any(|peer| peer.has_piece(piece_i)) || !web_seeds.is_empty(),
+            "none of the peers we connected to have piece {piece_i}"
+        );
+        need_pieces.push(Piece::new(piece_i, t));
+    }
+    let work = Scheduler::new(need_pieces, t.info.num_pieces());
+
+    // a participant can't take more work until its report is in the channel, so when we're slow
+    // to store pieces, peers stop downloading rather than piling finished pieces up in memory
+    let (report, mut reports) = mpsc::channel(REPORTS_PER_PEER * options.max_peers);
+    let participate = |mut peer: Peer, stop: CancellationToken| {
+        let work = &work;
+        let report = report.clone();
+        let swarm = swarm.clone();
+        async move {
+            if let Some(swarm) = &swarm {
+                swarm.joined(peer.addr());
+            }
+            let result = async {
+                if let Some(swarm) = &swarm {
+                    peer.enable_pex(swarm.clone()).await?;
+                }
+                peer.participate(
+                    work,
+                    report,
+                    options.pipeline_depth,
+                    options.block_size,
+                    options.block_timeout,
+                    stop.clone(),
+                )
                 .await
-                .expect("bound holds all these items");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        eprintln!("start receive loop");
-        let mut all_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    eprintln!("participant finished");
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
+            }
+            .await;
+            let addr = peer.addr();
+            if let Some(swarm) = &swarm {
+                swarm.left(addr);
+            }
+            let reason = match &result {
+                // banned, or the download is over one way or the other
+                _ if stop.is_cancelled() => "told to stop",
+                Ok(()) => "nothing more to offer",
+                Err(_) => "dropped out",
+            };
+            peer.disconnect(reason).await;
+            (addr, result)
+        }
+    };
+    let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
+    let mut web_seeding = futures_util::stream::futures_unordered::FuturesUnordered::new();
+    for seed in web_seeds {
+        let (work, report, stop) = (&work, report.clone(), cancel.child_token());
+        web_seeding.push(async move {
+            let url = seed.url().to_string();
+            (url, webseed::participate(seed, t, work, report, stop).await)
+        });
+    }
+    // lets us disconnect a single peer
+    let mut stops = HashMap::new();
+    // peers we're connected to, but aren't downloading from (yet) because we're at max_peers
+    let mut pending: Vec<Peer> = Vec::new();
+    for peer in peers {
+        let stop = cancel.child_token();
+        stops.insert(peer.addr(), stop.clone());
+        participants.push(participate(peer, stop));
+    }
+
+    // how many corrupt pieces each peer has sent us
+    let mut bad_pieces: HashMap<SocketAddr, usize> = HashMap::new();
+    // how many corrupt copies of each piece we've been sent, by any peer
+    let mut failed_attempts = vec![0; num_pieces];
+    // hosts that sent too many corrupt pieces, by IP since they may reconnect from another port
+    let mut banned = HashSet::new();
+    // downloaded pieces being hashed off the async runtime, with the peer that sent each
+    let mut verifying = tokio::task::JoinSet::new();
+    while !have.is_complete(num_pieces) {
+        // the piece to store this time around, and whether it was verified
+        let finished = tokio::select! {
+            _ = cancel.cancelled() => {
+                // dropping the participants (and the background tasks) when we return tears down
+                // all the peer connections
+                let stopped = TrackerRequest {
+                    downloaded: downloaded.load(Ordering::Relaxed),
+                    left: announce.left - downloaded.load(Ordering::Relaxed),
+                    event: Some(Event::Stopped),
+                    ..announce
+                };
+                if use_tracker {
+                    if let Err(e) = TrackerResponse::query(&client, t, info_hash, &stopped).await {
+                        warn!("failed to announce stop to tracker: {:#}", anyhow::Error::from(e));
+                    }
+                }
+                return Err(Error::Interrupted.into());
+            }
+            // once the pool is full, new peers back up in the channel, and `connect` stops
+            // connecting to more
+            Some(peer) = incoming.recv(), if pending.len() < options.max_peers => {
+                if banned.contains(&peer.addr().ip()) {
+                    background.spawn(peer.disconnect("banned"));
+                    continue;
+                }
+                pending.push(peer);
+                None
+            }
+            Some((peer, result)) = participants.next(), if !participants.is_empty() => {
+                stops.remove(&peer);
+                // any piece the peer was working on has already been handed back
+                match result {
+                    Ok(()) => debug!(%peer, "peer has nothing more to offer"),
+                    Err(e) => warn!(%peer, "peer dropped out: {e:#}"),
+                }
+                None
+            }
+            Some((url, result)) = web_seeding.next(), if !web_seeding.is_empty() => {
+                match result {
+                    Ok(()) => debug!(%url, "web seed has nothing more to do"),
+                    Err(e) => warn!(%url, "giving up on web seed: {e:#}"),
+                }
+                None
+            }
+            // a piece waiting to be hashed is a piece in memory, so stop taking reports while
+            // there are as many as we hash at once
+            Some(report) = reports.recv(), if verifying.len() < options.verify_threads => {
+                match report {
+                    Report::Downloaded { peer, piece, bytes } => {
+                        let piece_i = piece.index();
+                        if have.has_piece(piece_i) {
+                            // two peers raced to deliver the same piece in the end game; the
+                            // bytes are the same
+                            continue;
                         }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
+                        if banned.contains(&peer.ip()) {
+                            // it finished this piece before it noticed it was disconnected
+                            stats.retried_pieces.insert(piece_i);
+                            work.put_back(piece);
+                            continue;
                         }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
+                        if !options.verify {
+                            *stats.bytes_per_peer.entry(peer).or_insert(0) += piece.length();
+                            Some((piece, bytes, false))
+                        } else {
+                            let hash = t.info.pieces.0[piece_i];
+                            verifying.spawn(async move {
+                                (peer, piece, verify_blocking(bytes, hash).await)
+                            });
+                            None
                         }
                     }
+                    // the web seed already checked the piece's hash
+                    Report::WebSeeded { url, piece, bytes } => {
+                        if have.has_piece(piece.index()) {
+                            continue;
+                        }
+                        *stats.bytes_per_web_seed.entry(url).or_insert(0) += piece.length();
+                        Some((piece, bytes, true))
+                    }
+                    Report::Abandoned { piece } => {
+                        // unless it lost an end game race
+                        if !have.has_piece(piece.index()) {
+                            stats.retried_pieces.insert(piece.index());
+                            work.put_back(piece);
+                        }
+                        None
+                    }
+                }
+            }
+            Some(verified) = verifying.join_next(), if !verifying.is_empty() => {
+                let (peer, piece, verified) = verified.context("verify piece")?;
+                let (bytes, ok) = verified?;
+                let piece_i = piece.index();
+                if have.has_piece(piece_i) {
+                    continue;
+                }
+                if banned.contains(&peer.ip()) {
+                    // it was banned for the pieces it sent before this one
+                    stats.retried_pieces.insert(piece_i);
+                    work.put_back(piece);
+                    continue;
                 }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        eprintln!("got piece");
-                        // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participations have either exited or are
-                            // waiting for more work -- in either case, it is okay to drop all the
-                            // participant futures.
-                            break;
+                if !ok {
+                    let strikes = bad_pieces.entry(peer).or_insert(0);
+                    *strikes += 1;
+                    warn!(
+                        piece = piece_i,
+                        %peer,
+                        strikes,
+                        "piece failed hash verification"
+                    );
+                    if *strikes >= MAX_BAD_PIECES {
+                        warn!(
+                            %peer,
+                            "banning peer for sending {MAX_BAD_PIECES} corrupt pieces"
+                        );
+                        banned.insert(peer.ip());
+                        // along with any other connection from the same host, whose pieces we'd
+                        // only throw away
+                        for (addr, stop) in &stops {
+                            if addr.ip() == peer.ip() {
+                                stop.cancel();
+                            }
                         }
-                    } else {
-                        eprintln!("got pieces end");
-                        // there are no peers left, so we can't progress!
-                        break;
                     }
+                    stats.retried_pieces.insert(piece_i);
+                    let attempts = &mut failed_attempts[piece_i];
+                    *attempts += 1;
+                    if *attempts >= options.max_piece_attempts {
+                        // most likely the whole swarm has the same bad copy
+                        return Err(Error::PieceHashMismatch { piece: piece_i })
+                            .context(format!("giving up after {attempts} corrupt copies"));
+                    }
+                    work.put_back(piece);
+                    continue;
                 }
+                *stats.bytes_per_peer.entry(peer).or_insert(0) += piece.length();
+                Some((piece, bytes, true))
+            }
+        };
+        if let Some((piece, bytes, verified)) = finished {
+            let piece_i = piece.index();
+            storage
+                .write_piece(piece_i, &bytes)
+                .with_context(|| format!("store piece {piece_i}"))?;
+            have.set_piece(piece_i);
+            work.finish(piece_i);
+            downloaded.fetch_add(piece.length(), Ordering::Relaxed);
+            stats.bytes += piece.length();
+            let done = have.count_set();
+            if verified {
+                info!(piece = piece_i, done, of = num_pieces, "piece verified");
+            } else {
+                info!(
+                    piece = piece_i,
+                    done,
+                    of = num_pieces,
+                    "piece downloaded unverified"
+                );
             }
         }
-        drop(participants);
 
-        if bytes_received == piece_size {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+        while participants.len() < options.max_peers {
+            let Some(peer) = take_most_useful(&mut pending, have, num_pieces) else {
+                break;
+            };
+            debug!(peer = %peer.addr(), "new peer joined the download");
+            let stop = cancel.child_token();
+            stops.insert(peer.addr(), stop.clone());
+            participants.push(participate(peer, stop));
         }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher.finalize().into();
-        assert_eq!(hash, piece.hash());
+        // every report is sent before its participant finishes, so once both have run dry
+        // nobody is working on the missing pieces any more
+        let missing = num_pieces - have.count_set();
+        let idle = participants.is_empty()
+            && web_seeding.is_empty()
+            && reports.is_empty()
+            && verifying.is_empty();
+        if idle && missing > 0 {
+            // a re-announce might turn up more peers, but only after the tracker's interval,
+            // which is typically half an hour; better to say so than to sit there that long
+            anyhow::bail!("no peers left to get the remaining {missing} pieces from");
+        }
+    }
+    // wake up any participants still waiting for work so they see that we're done
+    work.close();
+    // hang up on every peer properly, rather than just dropping the connections
+    for stop in stops.values() {
+        stop.cancel();
+    }
+    while participants.next().await.is_some() {}
+    while web_seeding.next().await.is_some() {}
+    futures_util::future::join_all(
+        pending
+            .into_iter()
+            .map(|peer| peer.disconnect("download complete")),
+    )
+    .await;
 
-        all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
+    let completed = TrackerRequest {
+        downloaded: downloaded.load(Ordering::Relaxed),
+        left: 0,
+        event: Some(Event::Completed),
+        ..announce
+    };
+    if use_tracker {
+        if let Err(e) = TrackerResponse::query(&client, t, info_hash, &completed).await {
+            // we have the data either way
+            warn!(
+                "failed to announce completion to tracker: {:#}",
+                anyhow::Error::from(e)
+            );
+        }
     }
+    Ok(())
+}
 
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MutilFile { files } => files.clone(),
-        },
+/// Checks `bytes` against the SHA-1 `hash` on the blocking thread pool, since hashing a big piece
+/// on the async runtime would hold up every peer connection sharing its thread. Hands the bytes
+/// back along with whether they match.
+pub(crate) async fn verify_blocking(
+    bytes: Vec<u8>,
+    hash: [u8; 20],
+) -> anyhow::Result<(Vec<u8>, bool)> {
+    tokio::task::spawn_blocking(move || {
+        let ok = <[u8; 20]>::from(sha1::Sha1::digest(&bytes)) == hash;
+        (bytes, ok)
     })
+    .await
+    .context("hash piece")
+}
+
+/// Hashes every piece in `storage`, and returns the ones that match the torrent.
+///
+/// Pieces that can't be read (e.g. because a file is missing) just count as missing.
+fn verify_on_disk(t: &Torrent, storage: &impl Storage) -> Bitfield {
+    let num_pieces = t.info.num_pieces();
+    let mut have = Bitfield::empty(num_pieces);
+    for (piece_i, hash) in t.info.pieces.0.iter().enumerate() {
+        match storage.verify_piece(piece_i, hash) {
+            Ok(true) => have.set_piece(piece_i),
+            Ok(false) => debug!(
+                piece = piece_i,
+                "stored piece doesn't match, downloading it"
+            ),
+            Err(e) => debug!(piece = piece_i, "couldn't check stored piece: {e:#}"),
+        }
+    }
+    info!(
+        have = have.count_set(),
+        of = num_pieces,
+        "checked the pieces we already have"
+    );
+    have
+}
+
+/// What a peer sends back for each piece it took from the [`Scheduler`].
+pub(crate) enum Report {
+    /// The piece was downloaded from `peer`, but hasn't been checked against its hash yet.
+    Downloaded {
+        peer: SocketAddr,
+        piece: Piece,
+        bytes: Vec<u8>,
+    },
+    /// The piece was downloaded from the web seed at `url`, and matches its hash.
+    WebSeeded {
+        url: String,
+        piece: Piece,
+        bytes: Vec<u8>,
+    },
+    /// The peer (or web seed) couldn't finish the piece, e.g. because it choked us or
+    /// disconnected.
+    Abandoned { piece: Piece },
+}
+
+/// Removes the peer in `pending` that has the most pieces we don't, and returns it.
+///
+/// Peers that have none of the pieces we need are dropped along the way.
+fn take_most_useful(pending: &mut Vec<Peer>, have: &Bitfield, num_pieces: usize) -> Option<Peer> {
+    let useful = |peer: &Peer| {
+        (0..num_pieces)
+            .filter(|&piece_i| !have.has_piece(piece_i) && peer.has_piece(piece_i))
+            .count()
+    };
+    pending.retain(|peer| {
+        let keep = useful(peer) > 0;
+        if !keep {
+            debug!(peer = %peer.addr(), "dropping peer that has nothing we need");
+        }
+        keep
+    });
+    let best = pending
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, peer)| useful(peer))
+        .map(|(i, _)| i)?;
+    Some(pending.swap_remove(best))
+}
+
+/// Hands every peer that connects to us (and completes the handshake) over to the swarm.
+async fn accept(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    encryption: Encryption,
+    new_peers: mpsc::Sender<Peer>,
+) {
+    let mut handshakes = tokio::task::JoinSet::new();
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept peer connection: {e:#}");
+                continue;
+            }
+        };
+        let new_peers = new_peers.clone();
+        handshakes.spawn(async move {
+            match Peer::accept(stream, info_hash, num_pieces, encryption).await {
+                Ok(peer) => {
+                    // if the download is over, nobody wants the peer any more
+                    let _ = new_peers.send(peer).await;
+                }
+                Err(e) => {
+                    warn!(peer = %peer_addr, "failed to accept peer: {e:#}");
+                }
+            }
+        });
+        // reap the handshakes that have finished so the set doesn't grow forever
+        while handshakes.try_join_next().is_some() {}
+    }
+}
+
+/// Connects to every peer address we hear of that we haven't tried before, and hands the peers
+/// that work out over to the swarm.
+///
+/// At most `max_connects` peers are connecting or waiting for the swarm to take them at once; the
+/// addresses after that wait in `addrs`.
+async fn connect(
+    mut addrs: mpsc::UnboundedReceiver<SocketAddr>,
+    mut known: HashSet<SocketAddr>,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    max_connects: usize,
+    encryption: Encryption,
+    new_peers: mpsc::Sender<Peer>,
+) {
+    let slots = Arc::new(Semaphore::new(max_connects));
+    let mut connects = tokio::task::JoinSet::new();
+    while let Some(peer_addr) = addrs.recv().await {
+        if !known.insert(peer_addr) {
+            continue;
+        }
+        let Ok(slot) = Arc::clone(&slots).acquire_owned().await else {
+            return;
+        };
+        let new_peers = new_peers.clone();
+        connects.spawn(async move {
+            let _slot = slot;
+            match Peer::new(peer_addr, info_hash, num_pieces, encryption).await {
+                Ok(peer) => {
+                    let _ = new_peers.send(peer).await;
+                }
+                Err(e) => {
+                    warn!(peer = %peer_addr, "failed to connect to peer: {e:#}");
+                }
+            }
+        });
+        // reap the connects that have finished so the set doesn't grow forever
+        while connects.try_join_next().is_some() {}
+    }
+}
+
+/// Announces to the tracker every time the previous announce's interval runs out, and passes on
+/// the peers it tells us about.
+async fn reannounce(
+    client: reqwest::Client,
+    t: Torrent,
+    mut announce: TrackerRequest,
+    mut after: Duration,
+    downloaded: Arc<AtomicUsize>,
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+) {
+    let info_hash = t.info_hash();
+    // only the first announce is `started`
+    announce.event = None;
+    // what we had left to download when we started, not counting pieces we resumed with (or
+    // weren't asked for)
+    let left = announce.left;
+    loop {
+        tokio::time::sleep(after).await;
+
+        announce.downloaded = downloaded.load(Ordering::Relaxed);
+        announce.left = left - announce.downloaded;
+        let last = match TrackerResponse::query(&client, &t, info_hash, &announce).await {
+            Ok(response) => response,
+            Err(e) => {
+                // try again after another interval; the peers we have may well be enough
+                warn!(
+                    "failed to re-announce to tracker: {:#}",
+                    anyhow::Error::from(e)
+                );
+                continue;
+            }
+        };
+        after = last.reannounce_after();
+        announce.remember(&last);
+
+        for peer_addr in last.peer_addrs() {
+            if discovered.send(peer_addr).is_err() {
+                // the download is over
+                return;
+            }
+        }
+    }
+}
+
+/// Every file in the torrent, in order; a single-file torrent's one file is named after the
+/// torrent.
+pub(crate) fn files(t: &Torrent) -> Vec<File> {
+    match &t.info.keys {
+        Keys::SingleFile { length } => vec![File {
+            length: *length,
+            path: vec![t.info.name.clone()],
+            md5sum: None,
+        }],
+        Keys::MultiFile { files } => files.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_all_names_the_misassembled_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..3 * 16384).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let t = Torrent::create(&path, String::new(), Some(16384)).unwrap();
+
+        let downloaded = |bytes| Downloaded {
+            bytes,
+            files: files(&t),
+        };
+        assert!(downloaded(data.clone()).verify_all(&t).is_ok());
+
+        // the two 8 KiB blocks of piece 1 the wrong way round
+        let mut swapped = data.clone();
+        swapped[16384..2 * 16384].rotate_left(8192);
+        let e = downloaded(swapped).verify_all(&t).unwrap_err();
+        assert!(matches!(e, Error::PieceHashMismatch { piece: 1 }), "{e:?}");
+        assert_eq!(e.to_string(), "piece 1 does not match its hash");
+    }
 }
@@ -0,0 +1,48 @@
+use std::net::SocketAddr;
+
+/// The ways the core operations (decoding, talking to trackers and peers, downloading) can fail.
+///
+/// Anything that doesn't fit one of the specific kinds ends up in [`Error::Other`].
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The input isn't valid bencode, or doesn't have the shape we expected.
+    #[error("invalid bencode")]
+    BencodeDecode(#[from] serde_bencode::Error),
+
+    /// We couldn't get an answer out of the tracker.
+    #[error("tracker request failed")]
+    TrackerHttp(#[source] anyhow::Error),
+
+    /// We couldn't connect to a peer, or it didn't complete the handshake.
+    #[error("handshake with peer {peer} failed")]
+    HandshakeFailed {
+        peer: SocketAddr,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// A piece's data doesn't match its hash in the torrent.
+    #[error("piece {piece} does not match its hash")]
+    PieceHashMismatch { piece: usize },
+
+    /// A peer closed the connection when we still expected to hear from it.
+    #[error("peer {peer} disconnected")]
+    PeerDisconnected { peer: SocketAddr },
+
+    /// The download was cancelled before it finished.
+    #[error("download interrupted")]
+    Interrupted,
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        // an error that started out as one of ours keeps its kind, even with context added since
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Self::Other(e),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! A small BitTorrent client: reading and creating `.torrent` files, talking to trackers and peers,
+//! and downloading whole torrents.
+
+use anyhow::{anyhow, Context, Result};
+
+mod dht;
+pub mod download;
+mod error;
+mod metadata;
+mod mse;
+pub mod peer;
+mod pex;
+mod piece;
+mod scheduler;
+pub mod seed;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
+mod webseed;
+
+pub use error::Error;
+pub use peer::Peer;
+pub use storage::Storage;
+pub use torrent::{File, Info, Keys, Torrent};
+pub use tracker::TrackerResponse;
+
+/// The block size we request pieces in by default, which every peer accepts.
+pub const BLOCK_MAX: usize = 1 << 14;
+
+/// How long block `block` of a `piece_length`-byte piece is, when the piece is requested in
+/// blocks of `block_size` bytes: that many, except that the last block is cut short.
+///
+/// There are `piece_length.div_ceil(block_size)` blocks.
+pub fn block_length(piece_length: usize, block_size: usize, block: usize) -> usize {
+    block_size.min(piece_length - block * block_size)
+}
+
+/// Our peer id, used for every tracker announce and peer handshake.
+///
+/// It is fixed for the whole session (as private trackers require) rather than regenerated per
+/// announce.
+pub const PEER_ID: &[u8; 20] = b"00112233445566778899";
+
+/// Our tracker `key`, sent on every announce so a tracker can still tell it's us if our IP address
+/// changes.
+///
+/// Like [`PEER_ID`], it stays the same for the whole session: it's picked at random the first time
+/// it's used.
+pub static TRACKER_KEY: std::sync::LazyLock<String> =
+    std::sync::LazyLock::new(|| format!("{:08x}", rand::random::<u32>()));
+
+pub fn decode(encode: &str) -> Result<serde_json::Value, Error> {
+    decode_bytes(encode.as_bytes())
+}
+
+// bencoded data is bytes, not text: piece hashes and the like are rarely valid UTF-8
+pub fn decode_bytes(encode: &[u8]) -> Result<serde_json::Value, Error> {
+    let value = from_bencode(encode)?;
+    convert(value)
+}
+
+/// How deeply lists and dicts may nest in bencode we decode.
+///
+/// serde_bencode recurses once per level, so without a limit a few kilobytes of `l`s from a
+/// peer or tracker would overflow the stack.
+const MAX_NESTING: usize = 64;
+
+/// `serde_bencode::from_bytes`, but refusing input nested more than [`MAX_NESTING`] deep.
+///
+/// Use this for anything we didn't encode ourselves.
+pub(crate) fn from_bencode<T>(bytes: &[u8]) -> Result<T, serde_bencode::Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut depth = 0usize;
+    let mut i = 0;
+    // malformed input is left for serde_bencode to reject
+    while let Some(&b) = bytes.get(i) {
+        match b {
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_NESTING {
+                    return Err(serde_bencode::Error::InvalidValue(format!(
+                        "lists and dicts nest more than {MAX_NESTING} deep"
+                    )));
+                }
+                i += 1;
+            }
+            b'e' => {
+                depth = depth.saturating_sub(1);
+                i += 1;
+            }
+            b'i' => match bytes[i..].iter().position(|&b| b == b'e') {
+                Some(end) => i += end + 1,
+                None => break,
+            },
+            b'0'..=b'9' => {
+                let Some(colon) = bytes[i..].iter().position(|&b| b == b':') else {
+                    break;
+                };
+                let Some(len) = std::str::from_utf8(&bytes[i..i + colon])
+                    .ok()
+                    .and_then(|len| len.parse::<usize>().ok())
+                else {
+                    break;
+                };
+                i = (i + colon + 1).saturating_add(len);
+            }
+            _ => break,
+        }
+    }
+    serde_bencode::from_bytes(bytes)
+}
+
+/// The key of the JSON object [`convert`] writes a byte string that isn't UTF-8 as:
+/// `{"hex": "…"}`.
+const HEX: &str = "hex";
+
+/// The key of the JSON object [`convert`] writes a dict as when it can't be a plain JSON object:
+/// `{"dict": [[key, value], …]}`.
+const DICT: &str = "dict";
+
+// serde_bencode::value::Value -> serde_json::Value
+//
+// JSON strings can't hold arbitrary bytes, so a byte string (or dict key) that isn't UTF-8 is
+// written as `{"hex": "…"}`. A dict with such a key becomes `{"dict": [[key, value], …]}`, as does
+// a dict whose only key is "hex" or "dict", so that it isn't mistaken for one of these. That way
+// `unconvert` gets back exactly the bytes we started with.
+pub fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value, Error> {
+    match value {
+        serde_bencode::value::Value::Bytes(v) => Ok(convert_bytes(v)),
+        serde_bencode::value::Value::Int(i) => {
+            let integers = serde_json::Value::Number(i.into());
+            Ok(integers)
+        }
+        serde_bencode::value::Value::List(list) => {
+            let l = list
+                .into_iter()
+                .map(convert)
+                .collect::<Result<Vec<serde_json::Value>, _>>()?;
+            Ok(serde_json::Value::Array(l))
+        }
+        serde_bencode::value::Value::Dict(d) => {
+            let marker_like = d.len() == 1
+                && d.keys()
+                    .any(|k| k == HEX.as_bytes() || k == DICT.as_bytes());
+            if !marker_like && d.keys().all(|k| std::str::from_utf8(k).is_ok()) {
+                let mut map = serde_json::Map::new();
+                for (k, v) in d {
+                    let key = String::from_utf8(k).expect("dict keys were checked to be UTF-8");
+                    map.insert(key, convert(v)?);
+                }
+                return Ok(serde_json::Value::Object(map));
+            }
+            // in the order bencode keeps them
+            let mut d: Vec<_> = d.into_iter().collect();
+            d.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let entries = d
+                .into_iter()
+                .map(|(k, v)| {
+                    Ok(serde_json::Value::Array(vec![
+                        convert_bytes(k),
+                        convert(v)?,
+                    ]))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let mut map = serde_json::Map::new();
+            map.insert(DICT.to_string(), serde_json::Value::Array(entries));
+            Ok(serde_json::Value::Object(map))
+        }
+    }
+}
+
+fn convert_bytes(bytes: Vec<u8>) -> serde_json::Value {
+    match String::from_utf8(bytes) {
+        Ok(string) => serde_json::Value::String(string),
+        Err(e) => {
+            let mut map = serde_json::Map::new();
+            map.insert(
+                HEX.to_string(),
+                serde_json::Value::String(hex::encode(e.into_bytes())),
+            );
+            serde_json::Value::Object(map)
+        }
+    }
+}
+
+pub fn encode(json: &str) -> Result<Vec<u8>> {
+    let value = serde_json::from_str(json).context("parse JSON value")?;
+    let value = unconvert(value)?;
+    // NOTE: serde_bencode emits dict keys in sorted order, as bencode requires
+    serde_bencode::to_bytes(&value).map_err(|e| anyhow!(e.to_string()))
+}
+
+// serde_json::Value -> serde_bencode::value::Value
+pub fn unconvert(value: serde_json::Value) -> Result<serde_bencode::value::Value> {
+    match value {
+        serde_json::Value::String(s) => Ok(serde_bencode::value::Value::Bytes(s.into_bytes())),
+        serde_json::Value::Number(n) => {
+            let i = n
+                .as_i64()
+                .ok_or_else(|| anyhow!("bencode only supports integers, got {n}"))?;
+            Ok(serde_bencode::value::Value::Int(i))
+        }
+        serde_json::Value::Array(array) => {
+            let l = array
+                .into_iter()
+                .map(unconvert)
+                .collect::<Result<Vec<serde_bencode::value::Value>>>()?;
+            Ok(serde_bencode::value::Value::List(l))
+        }
+        serde_json::Value::Object(o) if o.len() == 1 && o.contains_key(HEX) => {
+            let hex = o[HEX]
+                .as_str()
+                .with_context(|| format!("{HEX:?} must be a hex string, got {}", o[HEX]))?;
+            let bytes = hex::decode(hex).with_context(|| format!("invalid hex {hex:?}"))?;
+            Ok(serde_bencode::value::Value::Bytes(bytes))
+        }
+        serde_json::Value::Object(mut o) if o.len() == 1 && o.contains_key(DICT) => {
+            let serde_json::Value::Array(entries) = o.remove(DICT).expect("checked above") else {
+                anyhow::bail!("{DICT:?} must be a list of [key, value] pairs");
+            };
+            let mut map = std::collections::HashMap::new();
+            for entry in entries {
+                let serde_json::Value::Array(entry) = entry else {
+                    anyhow::bail!("{DICT:?} entries must be [key, value] pairs, got {entry}");
+                };
+                let Ok([k, v]) = <[serde_json::Value; 2]>::try_from(entry) else {
+                    anyhow::bail!("{DICT:?} entries must be [key, value] pairs");
+                };
+                let serde_bencode::value::Value::Bytes(k) = unconvert(k)? else {
+                    anyhow::bail!("dict keys must be strings");
+                };
+                map.insert(k, unconvert(v)?);
+            }
+            Ok(serde_bencode::value::Value::Dict(map))
+        }
+        serde_json::Value::Object(o) => {
+            let mut map = std::collections::HashMap::new();
+            for (k, v) in o {
+                map.insert(k.into_bytes(), unconvert(v)?);
+            }
+            Ok(serde_bencode::value::Value::Dict(map))
+        }
+        serde_json::Value::Null => anyhow::bail!("bencode has no representation for null"),
+        serde_json::Value::Bool(b) => {
+            anyhow::bail!("bencode has no representation for booleans, got {b}")
+        }
+    }
+}
+
+/// Formats a byte count as e.g. `1.50 MiB (1572864 bytes)`.
+pub fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.2} {} ({bytes} bytes)", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_bytes(&bytes);
+        }
+
+        #[test]
+        fn decode_never_panics_on_bencode_like_bytes(bytes in "[ilde0-9:-]{0,256}") {
+            let _ = decode_bytes(bytes.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decode_regressions() {
+        // deep nesting used to overflow the stack
+        assert!(decode_bytes(&b"l".repeat(100_000)).is_err());
+        assert!(decode_bytes(&b"d1:a".repeat(10_000)).is_err());
+        // a string length that doesn't fit in a usize
+        assert!(decode_bytes(b"99999999999999999999:a").is_err());
+        // a string that's longer than the input
+        assert!(decode_bytes(b"5:abc").is_err());
+        assert!(decode_bytes(b"i12").is_err());
+        // nesting up to the limit is fine
+        let nested = [b"l".repeat(MAX_NESTING), b"e".repeat(MAX_NESTING)].concat();
+        assert!(decode_bytes(&nested).is_ok());
+    }
+
+    #[test]
+    fn block_lengths_at_other_block_sizes() {
+        // 100 KiB in 32 KiB blocks: three full blocks and a 4 KiB one
+        let lengths: Vec<_> = (0..(100usize << 10).div_ceil(32 << 10))
+            .map(|block| block_length(100 << 10, 32 << 10, block))
+            .collect();
+        assert_eq!(lengths, [32 << 10, 32 << 10, 32 << 10, 4 << 10]);
+
+        // a piece that's a multiple of the block size has no short block
+        let lengths: Vec<_> = (0..(256usize << 10).div_ceil(64 << 10))
+            .map(|block| block_length(256 << 10, 64 << 10, block))
+            .collect();
+        assert_eq!(lengths, [64 << 10; 4]);
+
+        // a block bigger than the piece is just the piece
+        assert_eq!(block_length(1000, BLOCK_MAX, 0), 1000);
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(1572864), "1.50 MiB (1572864 bytes)");
+        assert_eq!(human_size(1000), "1000 bytes");
+        assert_eq!(human_size(1024), "1.00 KiB (1024 bytes)");
+        assert_eq!(human_size(3 << 30), "3.00 GiB (3221225472 bytes)");
+    }
+
+    #[test]
+    fn binary_survives_decode_and_encode() {
+        // a dict that looks like a marker, a binary value, a string that looks like hex, and a
+        // binary key, in the order bencode sorts them
+        let bencoded: &[u8] = b"d4:dictd3:hex2:abe3:key2:\xff\x003:str6:6162632:\xfe\x01i3ee";
+        let decoded = decode_bytes(bencoded).unwrap();
+        assert_eq!(
+            decoded,
+            serde_json::json!({"dict": [
+                ["dict", {"dict": [["hex", "ab"]]}],
+                ["key", {"hex": "ff00"}],
+                ["str", "616263"],
+                [{"hex": "fe01"}, 3],
+            ]})
+        );
+        assert_eq!(encode(&decoded.to_string()).unwrap(), bencoded);
+
+        // and nothing changes for the UTF-8 strings almost everything has
+        let decoded = decode_bytes(b"d3:foo3:bar5:helloli52eee").unwrap();
+        assert_eq!(decoded, serde_json::json!({"foo": "bar", "hello": [52]}));
+        assert_eq!(
+            encode(&decoded.to_string()).unwrap(),
+            b"d3:foo3:bar5:helloli52eee"
+        );
+        assert!(encode(r#"{"hex": "xyz"}"#).is_err());
+        assert!(encode(r#"{"dict": [[1, 2]]}"#).is_err());
+    }
+}
@@ -1,92 +1,316 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::Context;
+use bittorrent_starter_rust::{
+    block_length, decode_bytes, download, encode, human_size,
+    peer::{self, Bitfield, Encryption, Handshake, Message, MessageFramer, MessageTag, Piece},
+    seed,
+    storage::FileStorage,
+    torrent,
+    tracker::{self, TrackerRequest, TrackerResponse},
+    Error, Storage, Torrent, BLOCK_MAX, PEER_ID,
+};
 use clap::{Parser, Subcommand};
 use futures_util::{SinkExt, StreamExt};
-use peer::Handshake;
-use sha1::{Digest, Sha1};
-use std::{net::SocketAddrV4, path::PathBuf};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracker::{urlencode, TrackerRequest, TrackerResponse};
-
-use crate::{
-    peer::{Message, MessageFramer, MessageTag, Piece, Request},
-    torrent::Torrent,
+use std::{
+    io::Write,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
 };
-
-pub mod download;
-pub mod peer;
-pub mod piece;
-pub mod torrent;
-pub mod tracker;
-
-pub const BLOCK_MAX: usize = 1 << 14;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_util::{codec::Framed, sync::CancellationToken};
 
 #[derive(Debug, Parser)]
 pub struct Args {
     #[command(subcommand)]
     command: Command,
+    /// The most verbose level of log messages to print (error, warn, info, debug or trace).
+    ///
+    /// Defaults to warn, or error with --quiet and info with --verbose.
+    #[arg(long, global = true)]
+    log_level: Option<tracing::Level>,
+    /// Only print each command's result (and errors), e.g. for scripts.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print more detail along with each command's result.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Send tracker (and web seed) requests through this proxy, e.g. `http://proxy:3128`.
+    ///
+    /// Without it, the standard HTTP_PROXY, HTTPS_PROXY and ALL_PROXY variables are used.
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+}
+
+/// How much to print besides each command's result, which is always printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
-// snake case for subcommands
+// snake case for subcommands, but kebab case for their flags (like --log-level), which a
+// variant's own rename_all sets; it renames the variant too, hence download_piece's name
 #[clap(rename_all = "snake_case")]
 pub enum Command {
     Decode {
+        /// The bencoded value; read from `--file` or stdin when omitted.
+        value: Option<String>,
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    Encode {
+        /// The JSON value to bencode.
         value: String,
     },
     Info {
         torrent: PathBuf,
+        /// Print the metadata as a JSON object instead.
+        #[arg(long)]
+        json: bool,
     },
     Peers {
         torrent: PathBuf,
+        /// `text` prints one `ip:port` per line; `json` prints an array of `{ "ip", "port" }`.
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+        /// How many peers to ask the tracker for, if not its default (usually 50).
+        #[arg(long)]
+        numwant: Option<usize>,
     },
     Handshake {
         torrent: PathBuf,
-        peer: String,
+        /// `ip:port`, `[ipv6]:port` or `host:port`.
+        #[arg(value_parser = peer::parse_peer)]
+        peer: SocketAddr,
     },
+    #[command(name = "download_piece", rename_all = "kebab-case")]
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
+        /// Don't check the piece against its hash. Unsafe: a corrupt piece is written out as-is.
+        #[arg(long)]
+        no_verify: bool,
+        /// Download from this peer (`ip:port` or `host:port`) instead of asking the tracker for
+        /// one. If given more than once, the first is used.
+        #[arg(long, value_parser = peer::parse_peer)]
+        peer: Vec<SocketAddr>,
+        /// How many bytes to request at a time, at most 131063. Many peers refuse more than the
+        /// default.
+        #[arg(long, default_value_t = BLOCK_MAX)]
+        block_size: usize,
     },
+    #[command(rename_all = "kebab-case")]
     Download {
+        /// Where to write the download. For a single-file torrent this is the file itself; for a
+        /// multi-file torrent it's a directory, and the files go in `<output>/<name>/`.
+        ///
+        /// Defaults to the torrent's name in the current directory.
         #[arg(short)]
-        output: PathBuf,
+        output: Option<PathBuf>,
+        #[arg(required_unless_present = "info_hash")]
+        torrent: Option<PathBuf>,
+        /// Download the torrent with this (hex) info hash instead of reading a torrent file,
+        /// fetching its metadata from the peers given with --peer, or else from peers in the DHT.
+        #[arg(long, value_parser = parse_info_hash, conflicts_with = "torrent")]
+        info_hash: Option<[u8; 20]>,
+        /// With --info-hash, don't fetch metadata of more than this many bytes from a peer.
+        #[arg(long, default_value_t = torrent::DEFAULT_MAX_METADATA_SIZE)]
+        max_metadata_size: usize,
+        /// Re-verify the fully assembled download before writing it out.
+        #[arg(long)]
+        paranoid: bool,
+        /// The port to accept peer connections on (falls back to 6881-6889 if taken).
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// How many block requests to keep in flight with each peer.
+        #[arg(long, default_value_t = 5)]
+        pipeline_depth: usize,
+        /// How many bytes to request at a time, at most 131063. Many peers refuse more than the
+        /// default.
+        #[arg(long, default_value_t = BLOCK_MAX)]
+        block_size: usize,
+        /// The most peers to download from at once; other peers wait until one drops out.
+        ///
+        /// This is the only way to go easier on a slow link: there is no --max-download-rate.
+        #[arg(long, default_value_t = 30)]
+        max_peers: usize,
+        /// Don't check pieces against their hashes. Unsafe: corrupt data from a bad peer is
+        /// written out as-is.
+        #[arg(long, conflicts_with = "paranoid")]
+        no_verify: bool,
+        /// How many downloaded pieces to hash at once, each on its own thread. Defaults to the
+        /// number of CPUs.
+        #[arg(long, conflicts_with = "no_verify")]
+        verify_threads: Option<usize>,
+        /// Give up once this many copies of the same piece have failed hash verification.
+        #[arg(long, default_value_t = 5)]
+        max_piece_attempts: usize,
+        /// Whether to encrypt peer connections: require, prefer (falling back to plaintext) or
+        /// disable.
+        #[arg(long, default_value_t = Encryption::Disable)]
+        encryption: Encryption,
+        /// Download from this peer (`ip:port` or `host:port`) instead of asking the tracker; may
+        /// be given more than once.
+        #[arg(long, value_parser = peer::parse_peer)]
+        peer: Vec<SocketAddr>,
+        /// Refuse to download torrents of more than this many bytes.
+        #[arg(long)]
+        max_size: Option<usize>,
+        /// How many peers to ask the tracker for on each announce, if not its default (usually
+        /// 50).
+        #[arg(long)]
+        numwant: Option<usize>,
+        /// Carry on with a download that's already (partly) at the output path. Every piece
+        /// there is checked against its hash, and only the missing or changed ones are
+        /// downloaded.
+        #[arg(long)]
+        resume: bool,
+        /// Like --resume, but trust the record of completed pieces that an unfinished download
+        /// leaves next to its output (in `<output>.resume`) instead of hashing every piece. If
+        /// the files have changed since, every piece is hashed after all.
+        #[arg(long, conflicts_with = "resume")]
+        fast_resume: bool,
+        /// Only download these pieces, e.g. `0,3,5-9`, writing them where they belong in the
+        /// output and leaving the rest of it alone (it's created empty if it doesn't exist).
+        #[arg(long, value_delimiter = ',', value_parser = parse_piece_range)]
+        select: Vec<RangeInclusive<usize>>,
+    },
+    /// Upload a complete download to anyone who asks, until interrupted.
+    Seed {
         torrent: PathBuf,
+        /// The downloaded data: the file itself for a single-file torrent, or the directory the
+        /// torrent's own directory is in for a multi-file one (as for `download -o`).
+        file: PathBuf,
+        /// The port to accept peer connections on (falls back to 6881-6889 if taken).
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Whether to accept encrypted connections: require, prefer or disable.
+        #[arg(long, default_value_t = Encryption::Disable)]
+        encryption: Encryption,
+    },
+    /// Show how a download would be split up (pieces, blocks, files), without downloading.
+    Plan { torrent: PathBuf },
+    /// Check a download against the piece hashes.
+    Verify {
+        torrent: PathBuf,
+        /// The download, as given to `download -o`: the file itself for a single-file torrent, or
+        /// for a multi-file torrent the directory its files are in, under `<file>/<name>/`.
+        file: PathBuf,
+    },
+    /// Make a .torrent for a file or directory.
+    #[command(rename_all = "kebab-case")]
+    Create {
+        input: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        /// The tracker's announce URL.
+        #[arg(long)]
+        announce: String,
+        /// Bytes per piece: a power of two from 16 KiB to 16 MiB (by default, whatever gives
+        /// 1000-2000 pieces).
+        #[arg(long)]
+        piece_length: Option<usize>,
     },
 }
 
-pub fn decode(encode: &str) -> Result<serde_json::Value> {
-    let value = serde_bencode::from_str(encode).map_err(|e| anyhow!(e.to_string()))?;
-    convert(value)
+/// Checks that the peer's side of a handshake is for the protocol we speak.
+fn check_handshake(handshake: &Handshake) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        handshake.length == 19 && &handshake.bittorrent == b"BitTorrent protocol",
+        "peer replied with a handshake for some other protocol"
+    );
+    Ok(())
 }
 
-// serde_bencode::value::Value -> serde_json::Value
-pub fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value> {
-    match value {
-        serde_bencode::value::Value::Bytes(v) => {
-            let string = String::from_utf8(v)?;
-            Ok(serde_json::Value::String(string))
-        }
-        serde_bencode::value::Value::Int(i) => {
-            let integers = serde_json::Value::Number(i.into());
-            Ok(integers)
+/// The next message from `peer`, giving up on it if it goes quiet as long as a download would.
+async fn next_message(
+    peer: &mut Framed<TcpStream, MessageFramer>,
+    peer_addr: SocketAddr,
+    what: &str,
+) -> anyhow::Result<Message> {
+    tokio::time::timeout(peer::BLOCK_TIMEOUT, peer.next())
+        .await
+        .with_context(|| format!("peer went quiet while we waited for {what}"))?
+        .ok_or(Error::PeerDisconnected { peer: peer_addr })
+        .with_context(|| format!("wait for {what}"))?
+        .context("peer message was invalid")
+}
+
+/// A token that's cancelled on the first Ctrl-C, so we can shut down cleanly; a second Ctrl-C
+/// exits at once.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("interrupted, shutting down (press Ctrl-C again to force)");
+                cancel.cancel();
+            }
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
         }
-        serde_bencode::value::Value::List(list) => {
-            let l = list
-                .into_iter()
-                .map(convert)
-                .collect::<Result<Vec<serde_json::Value>>>()?;
-            Ok(serde_json::Value::Array(l))
+    });
+    cancel
+}
+
+/// The `peers --format json` output: an array of `{"ip": …, "port": …}` objects.
+fn peers_json(peers: impl Iterator<Item = SocketAddr>) -> serde_json::Value {
+    peers
+        .map(|peer| serde_json::json!({ "ip": peer.ip(), "port": peer.port() }))
+        .collect()
+}
+
+/// Refuses a torrent of `length` bytes if that's more than `--max-size`.
+fn check_max_size(length: usize, max_size: Option<usize>) -> anyhow::Result<()> {
+    if let Some(max_size) = max_size {
+        anyhow::ensure!(
+            length <= max_size,
+            "torrent is {}, more than the {} allowed by --max-size",
+            human_size(length),
+            human_size(max_size)
+        );
+    }
+    Ok(())
+}
+
+/// Parses a torrent's info hash, given as 40 hex characters.
+fn parse_info_hash(s: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = hex::decode(s).context("info hash isn't hex")?;
+    <[u8; 20]>::try_from(bytes)
+        .map_err(|bytes| anyhow::anyhow!("info hash is {} bytes, not 20", bytes.len()))
+}
+
+/// Parses a piece index, or an inclusive range of them like `5-9`.
+fn parse_piece_range(s: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .with_context(|| format!("{n:?} isn't a piece index"))
+    };
+    match s.split_once('-') {
+        Some((first, last)) => {
+            let (first, last) = (parse(first)?, parse(last)?);
+            anyhow::ensure!(first <= last, "range {s} runs backwards");
+            Ok(first..=last)
         }
-        serde_bencode::value::Value::Dict(d) => {
-            let mut map = serde_json::Map::new();
-            for (k, v) in d {
-                let key = String::from_utf8(k)?;
-                let value = convert(v)?;
-                map.insert(key, value);
-            }
-            Ok(serde_json::Value::Object(map))
+        None => {
+            let piece_i = parse(s)?;
+            Ok(piece_i..=piece_i)
         }
     }
 }
@@ -95,258 +319,618 @@ pub fn convert(value: serde_bencode::value::Value) -> Result<serde_json::Value>
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
     let arg = Args::parse();
+    let verbosity = match (arg.quiet, arg.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    let log_level = arg.log_level.unwrap_or(match verbosity {
+        Verbosity::Quiet => tracing::Level::ERROR,
+        Verbosity::Normal => tracing::Level::WARN,
+        Verbosity::Verbose => tracing::Level::INFO,
+    });
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_writer(std::io::stderr)
+        .init();
     match arg.command {
-        Command::Decode { value } => {
-            let decoded_value = decode(&value);
-            println!("{:?}", decoded_value);
+        Command::Decode { value, file } => {
+            let encoded = match (value, file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) => tokio::fs::read(file).await.context("read input file")?,
+                (None, None) => {
+                    let mut buf = Vec::new();
+                    tokio::io::stdin()
+                        .read_to_end(&mut buf)
+                        .await
+                        .context("read stdin")?;
+                    buf
+                }
+            };
+            let decoded_value = decode_bytes(&encoded);
+            if verbosity >= Verbosity::Verbose {
+                println!("{:?}", decoded_value);
+            }
             match decoded_value {
                 Ok(value) => {
                     println!("{}", value);
                 }
                 Err(e) => {
-                    println!("Error: {}", e);
+                    println!("Error: {:#}", anyhow::Error::from(e));
                 }
             }
         }
-        Command::Info { torrent } => {
-            let file = std::fs::read(torrent)?;
-            let t: Torrent = serde_bencode::from_bytes(&file).context("parse torrent file")?;
-            println!("Tracker url {:?}", t.announce);
-            if let torrent::Keys::SingleFile { length } = t.info.keys {
-                println!("File length: {}", length);
-            } else {
-                todo!("Handle multi-file torrents");
+        Command::Encode { value } => {
+            let encoded = encode(&value)?;
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&encoded).context("write encoded value")?;
+            writeln!(stdout).context("write encoded value")?;
+        }
+        Command::Info { torrent, json } => {
+            let t = Torrent::read(torrent).await?;
+            if json {
+                let mut info = serde_json::json!({
+                    "announce": t.announce,
+                    "info_hash": hex::encode(t.info_hash()),
+                    "piece_length": t.info.plength,
+                    "piece_count": t.info.num_pieces(),
+                    "total_length": t.length(),
+                });
+                for (key, value) in [
+                    ("creation_date", t.creation_date.map(Into::into)),
+                    ("comment", t.comment.as_deref().map(Into::into)),
+                    ("created_by", t.created_by.as_deref().map(Into::into)),
+                    ("encoding", t.encoding.as_deref().map(Into::into)),
+                ] {
+                    if let Some(value) = value {
+                        info[key] = value;
+                    }
+                }
+                if let torrent::Keys::MultiFile { files } = &t.info.keys {
+                    info["files"] = files
+                        .iter()
+                        .map(|file| serde_json::json!({ "path": file.path, "length": file.length }))
+                        .collect();
+                }
+                println!("{info}");
+                return Ok(());
             }
+            println!("Tracker url {:?}", t.announce);
+            println!("File length: {}", human_size(t.length()));
             let hash_info = t.info_hash();
             println!("Info Hash: {}", hex::encode(hash_info));
             println!("Piece Length: {}", t.info.plength);
+            if t.is_private() {
+                println!("Private: yes");
+            }
+            if let Some(creation_date) = t.creation_date {
+                println!("Creation Date: {creation_date}");
+            }
+            if let Some(comment) = &t.comment {
+                println!("Comment: {comment}");
+            }
+            if let Some(created_by) = &t.created_by {
+                println!("Created By: {created_by}");
+            }
+            if let Some(encoding) = &t.encoding {
+                println!("Encoding: {encoding}");
+            }
+            for url in t.url_list.iter().flatten() {
+                println!("Web Seed: {url}");
+            }
             println!("Pieces Hashes:");
             for hash in t.info.pieces.0 {
                 print!("{}", hex::encode(hash));
             }
         }
-        Command::Peers { torrent } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-
+        Command::Peers {
+            torrent,
+            format,
+            numwant,
+        } => {
+            let t = Torrent::read(torrent).await?;
             let info_hash = t.info_hash();
+            // left is the whole torrent, however many files it has
             let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
+                event: None,
+                numwant,
+                ..TrackerRequest::new(&t, 6881)
             };
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-            for peer in &response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+            let client = tracker::client(arg.proxy.as_deref())?;
+            let response = TrackerResponse::query(&client, &t, info_hash, &request)
+                .await
+                .context("query tracker")?;
+            match format {
+                Format::Text => {
+                    for peer in response.peer_addrs() {
+                        println!("{peer}");
+                    }
+                }
+                Format::Json => println!("{}", peers_json(response.peer_addrs())),
             }
         }
         Command::Handshake { torrent, peer } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::read(torrent).await?;
 
             let info_hash = t.info_hash();
-            let peer = peer.parse::<SocketAddrV4>().context("parse peer address")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            let mut peer = TcpStream::connect(peer).await.context("connect to peer")?;
+            let mut handshake = Handshake::new(info_hash, *PEER_ID);
             {
-                // copy from joohoo's code
-                let handshake_bytes =
-                    &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-                // Safety: Handshake is a POD with repr(c) and repr(packed)
-                let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-                    unsafe { &mut *handshake_bytes };
+                let handshake_bytes = handshake.as_bytes_mut();
                 peer.write_all(handshake_bytes)
                     .await
                     .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+                peer::read_handshake(&mut peer, handshake_bytes, peer::HANDSHAKE_IDLE_TIMEOUT)
+                    .await?;
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            check_handshake(&handshake)?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id()));
         }
         Command::DownloadPiece {
             output,
             torrent,
             piece: piece_i,
+            no_verify,
+            peer: peers,
+            block_size,
         } => {
-            // comples code
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-            assert!(piece_i < t.info.pieces.0.len());
+            let t = Torrent::read(torrent).await?;
+            anyhow::ensure!(
+                piece_i < t.info.num_pieces(),
+                "piece {piece_i} is out of range; the torrent has {} pieces",
+                t.info.num_pieces()
+            );
+            anyhow::ensure!(
+                (1..=peer::MAX_BLOCK_SIZE).contains(&block_size),
+                "block size must be from 1 to {} bytes, not {block_size}",
+                peer::MAX_BLOCK_SIZE
+            );
 
             let info_hash = t.info_hash();
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
+            let peer_addr = match peers.first() {
+                Some(&peer) => peer,
+                None => {
+                    let request = TrackerRequest {
+                        event: None,
+                        ..TrackerRequest::new(&t, 6881)
+                    };
+                    let client = tracker::client(arg.proxy.as_deref())?;
+                    let tracker_info = TrackerResponse::query(&client, &t, info_hash, &request)
+                        .await
+                        .context("query tracker")?;
+                    let peer = tracker_info.peer_addrs().next();
+                    peer.context("tracker returned no peers")?
+                }
             };
-
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-
-            let peer = &tracker_info.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            let mut peer = TcpStream::connect(peer_addr)
                 .await
                 .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            let mut handshake = Handshake::new(info_hash, *PEER_ID);
             {
                 let handshake_bytes = handshake.as_bytes_mut();
                 peer.write_all(handshake_bytes)
                     .await
                     .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+                peer::read_handshake(&mut peer, handshake_bytes, peer::HANDSHAKE_IDLE_TIMEOUT)
+                    .await?;
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
+            check_handshake(&handshake)?;
 
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-            // NOTE: we assume that the bitfield covers all pieces
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested message")?;
+            let mut peer = Framed::new(peer, MessageFramer);
+            let bitfield = next_message(&mut peer, peer_addr, "bitfield").await?;
+            anyhow::ensure!(
+                bitfield.tag == MessageTag::Bitfield,
+                "peer sent {:?} where we expected its bitfield",
+                bitfield.tag
+            );
+            anyhow::ensure!(
+                Bitfield::from_payload(bitfield.payload).has_piece(piece_i),
+                "peer {peer_addr} doesn't have piece {piece_i}"
+            );
 
-            let unchoke = peer
-                .next()
+            peer.send(Message::interested())
                 .await
-                .expect("peer always sends an unchoke")
-                .context("peer message was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
+                .context("send interested message")?;
 
-            let piece_hash = &t.info.pieces.0[piece_i];
-            let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
-                let md = length % t.info.plength;
-                if md == 0 {
-                    t.info.plength
-                } else {
-                    md
-                }
-            } else {
-                t.info.plength
-            };
-            // the + (BLOCK_MAX - 1) rounds up
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
+            let unchoke = next_message(&mut peer, peer_addr, "unchoke").await?;
+            anyhow::ensure!(
+                unchoke.tag == MessageTag::Unchoke,
+                "peer sent {:?} where we expected it to unchoke us",
+                unchoke.tag
+            );
+            anyhow::ensure!(
+                unchoke.payload.is_empty(),
+                "peer sent an unchoke message with a payload"
+            );
+
+            let piece_size = t.info.piece_size(piece_i);
+            let nblocks = piece_size.div_ceil(block_size);
             let mut all_blocks = Vec::with_capacity(piece_size);
             for block in 0..nblocks {
-                let block_size = if block == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
-                    }
-                } else {
-                    BLOCK_MAX
-                };
-                let mut request = Request::new(
+                let block_length = block_length(piece_size, block_size, block);
+                let request = Message::request(
                     piece_i as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
+                    (block * block_size) as u32,
+                    block_length as u32,
                 );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
-
-                let piece = peer
-                    .next()
+                peer.send(request)
                     .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
+                    .with_context(|| format!("send request for block {block}"))?;
 
-                let piece = Piece::ref_from_bytes(&piece.payload[..])
-                    .expect("always get all Piece response fields from peer");
-                assert_eq!(piece.index() as usize, piece_i);
-                assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                assert_eq!(piece.block().len(), block_size);
+                let msg = loop {
+                    let msg = next_message(&mut peer, peer_addr, &format!("block {block}")).await?;
+                    match msg.tag {
+                        MessageTag::Piece => break msg,
+                        // it's just telling us about the pieces it has
+                        MessageTag::Have => continue,
+                        tag => anyhow::bail!("peer sent {tag:?} while we waited for block {block}"),
+                    }
+                };
+                let piece = Piece::ref_from_bytes(&msg.payload[..]).with_context(|| {
+                    format!("peer sent a truncated piece message for block {block}")
+                })?;
+                let begin = block * block_size;
+                // we only ever have the one request outstanding, so anything else is the peer's
+                // mistake
+                anyhow::ensure!(
+                    piece.index() as usize == piece_i && piece.begin() as usize == begin,
+                    "peer sent the block at {} of piece {}, but we asked for the one at {begin} of \
+                     piece {piece_i}",
+                    piece.begin(),
+                    piece.index()
+                );
+                anyhow::ensure!(
+                    piece.block().len() == block_length,
+                    "peer sent {} bytes for block {block}, but we asked for {block_length}",
+                    piece.block().len()
+                );
                 all_blocks.extend(piece.block());
             }
-            assert_eq!(all_blocks.len(), piece_size);
 
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher.finalize().into();
-            assert_eq!(&hash, piece_hash);
+            if !no_verify && !t.info.verify_piece(piece_i, &all_blocks) {
+                return Err(Error::PieceHashMismatch { piece: piece_i }.into());
+            }
 
             tokio::fs::write(&output, all_blocks)
                 .await
                 .context("write out downloaded piece")?;
             println!("Piece {piece_i} downloaded to {}.", output.display());
         }
-        Command::Download { output, torrent } => {
+        Command::Download {
+            output,
+            torrent,
+            info_hash,
+            max_metadata_size,
+            paranoid,
+            port,
+            pipeline_depth,
+            block_size,
+            max_peers,
+            no_verify,
+            verify_threads,
+            max_piece_attempts,
+            encryption,
+            peer: peers,
+            max_size,
+            numwant,
+            resume,
+            fast_resume,
+            select,
+        } => {
+            let torrent = match (torrent, info_hash) {
+                (Some(torrent), _) => Torrent::read(torrent).await?,
+                (None, Some(info_hash)) => {
+                    Torrent::from_info_hash(info_hash, &peers, encryption, max_metadata_size)
+                        .await
+                        .context("fetch torrent metadata")?
+                }
+                (None, None) => unreachable!("clap requires a torrent or an info hash"),
+            };
+            if verbosity >= Verbosity::Normal {
+                torrent.print_tree();
+            }
+            check_max_size(torrent.length(), max_size)?;
+            let select = (!select.is_empty()).then(|| {
+                let mut pieces: Vec<usize> = select.into_iter().flatten().collect();
+                pieces.sort_unstable();
+                pieces.dedup();
+                pieces
+            });
+            // check where the download goes before spending any time on it
+            let multi_file = matches!(torrent.info.keys, torrent::Keys::MultiFile { .. });
+            let output = match output {
+                Some(output) => output,
+                // the files of a multi-file torrent already go in a directory with its name
+                None if multi_file => PathBuf::from("."),
+                None => {
+                    let name = Path::new(torrent.name());
+                    // the name comes from the torrent, so it mustn't point anywhere else
+                    anyhow::ensure!(
+                        name.file_name() == Some(name.as_os_str()),
+                        "torrent name {:?} isn't a plain file name; pass -o to say where to put it",
+                        torrent.name()
+                    );
+                    name.to_path_buf()
+                }
+            };
+            if multi_file {
+                anyhow::ensure!(
+                    !output.exists() || output.is_dir(),
+                    "{} is a file, but a multi-file torrent needs a directory to go in",
+                    output.display()
+                );
+            } else {
+                anyhow::ensure!(
+                    !output.is_dir(),
+                    "{} is a directory, but a single-file torrent is written to a file",
+                    output.display()
+                );
+            }
+            let cancel = cancel_on_ctrl_c();
+            let options = download::Options {
+                port,
+                pipeline_depth,
+                block_size,
+                block_timeout: peer::BLOCK_TIMEOUT,
+                max_peers,
+                verify: !no_verify,
+                verify_threads: verify_threads
+                    .unwrap_or_else(|| download::Options::default().verify_threads),
+                max_piece_attempts,
+                encryption,
+                peers,
+                numwant,
+                proxy: arg.proxy,
+                pieces: select.clone(),
+                resume,
+                fast_resume,
+            };
+            // pieces go straight to disk as they arrive, so the torrent needn't fit in memory
+            let existing = if multi_file {
+                output.join(torrent.name())
+            } else {
+                output.clone()
+            };
+            // a partial download keeps whatever isn't downloaded again
+            let storage = if (resume || fast_resume || select.is_some()) && existing.exists() {
+                FileStorage::open(&torrent.info, &output).context("open download to resume")?
+            } else {
+                FileStorage::create(&torrent.info, &output)?
+            };
+            // so that an unfinished download can be picked up again with --fast-resume
+            let mut resume_file = existing.into_os_string();
+            resume_file.push(".resume");
+            let mut storage =
+                storage.with_resume_file(PathBuf::from(resume_file), torrent.info_hash());
+            let stats = torrent
+                .download_into(&mut storage, &options, cancel)
+                .await?;
+            if paranoid {
+                let selected =
+                    |piece_i: &usize| select.as_ref().is_none_or(|s| s.contains(piece_i));
+                for (piece_i, hash) in torrent.info.pieces.0.iter().enumerate() {
+                    if !selected(&piece_i) {
+                        continue;
+                    }
+                    if !storage
+                        .verify_piece(piece_i, hash)
+                        .context("verify downloaded files")?
+                    {
+                        return Err(Error::PieceHashMismatch { piece: piece_i })
+                            .context("verify downloaded files");
+                    }
+                }
+            }
+            println!(
+                "Downloaded {} in {:.1}s ({:.2} MiB/s) from {} peers and {} web seeds.",
+                human_size(stats.bytes),
+                stats.elapsed.as_secs_f64(),
+                stats.bytes_per_second() / (1024.0 * 1024.0),
+                stats.peers_used(),
+                stats.bytes_per_web_seed.len()
+            );
+            if verbosity >= Verbosity::Normal && !stats.retried_pieces.is_empty() {
+                let pieces: Vec<_> = stats.retried_pieces.iter().map(|i| i.to_string()).collect();
+                println!("Retried pieces: {}", pieces.join(", "));
+            }
+            if verbosity >= Verbosity::Verbose {
+                for (peer, bytes) in &stats.bytes_per_peer {
+                    println!("  {peer}: {}", human_size(*bytes));
+                }
+                for (url, bytes) in &stats.bytes_per_web_seed {
+                    println!("  {url} (web seed): {}", human_size(*bytes));
+                }
+            }
+        }
+        Command::Seed {
+            torrent,
+            file,
+            port,
+            encryption,
+        } => {
             let torrent = Torrent::read(torrent).await?;
-            torrent.print_tree();
-            // torrent.download_all_to_file(output).await?;
-            let files = torrent.download_all().await?;
-            tokio::fs::write(
-                output,
-                files.into_iter().next().expect("always one file").bytes(),
-            )
-            .await?;
+            let storage = FileStorage::open(&torrent.info, &file)?;
+            let cancel = cancel_on_ctrl_c();
+            let options = seed::Options {
+                port,
+                encryption,
+                proxy: arg.proxy,
+            };
+            if verbosity >= Verbosity::Normal {
+                println!("Seeding {}. Press Ctrl-C to stop.", torrent.info.name);
+            }
+            torrent.seed(storage, &options, cancel).await?;
+        }
+        Command::Plan { torrent } => {
+            let t = Torrent::read(torrent).await?;
+            let num_pieces = t.info.num_pieces();
+            println!("Total length: {}", human_size(t.length()));
+            println!("Pieces: {num_pieces}");
+            println!("Piece length: {}", t.info.plength);
+            if num_pieces == 0 {
+                return Ok(());
+            }
+            println!("Last piece length: {}", t.info.piece_size(num_pieces - 1));
+            let blocks: usize = (0..num_pieces)
+                .map(|piece_i| t.info.piece_size(piece_i).div_ceil(BLOCK_MAX))
+                .sum();
+            println!("Blocks: {blocks} (of up to {BLOCK_MAX} bytes)");
+
+            if let torrent::Keys::MultiFile { files } = &t.info.keys {
+                println!("Files:");
+                let mut offset = 0;
+                for file in files {
+                    let path = file.path.join("/");
+                    if file.length == 0 {
+                        println!("  {path}: empty, no pieces");
+                    } else {
+                        let first = offset / t.info.plength;
+                        let last = (offset + file.length - 1) / t.info.plength;
+                        println!(
+                            "  {path}: {} bytes at offset {offset}, pieces {first}-{last}",
+                            file.length
+                        );
+                    }
+                    offset += file.length;
+                }
+            }
+        }
+        Command::Verify { torrent, file } => {
+            let t = Torrent::read(torrent).await?;
+            // the same layout (and path checks) as the download that wrote it, a piece at a time
+            let storage = FileStorage::open(&t.info, &file).context("open download to verify")?;
+
+            let mut failed = 0;
+            for piece_i in 0..t.info.num_pieces() {
+                let status = match storage.verify_piece(piece_i, &t.info.pieces.0[piece_i]) {
+                    Ok(true) => "ok",
+                    Ok(false) => "bad",
+                    Err(e) => {
+                        eprintln!("could not read piece {piece_i}: {e:#}");
+                        "unreadable"
+                    }
+                };
+                if status != "ok" {
+                    failed += 1;
+                }
+                // the pieces that failed are the result; the rest is progress
+                if status != "ok" || verbosity >= Verbosity::Normal {
+                    println!("Piece {piece_i}: {status}");
+                }
+            }
+            if failed != 0 {
+                anyhow::bail!(
+                    "{failed} of {} pieces failed verification",
+                    t.info.num_pieces()
+                );
+            }
+            println!("All {} pieces verified.", t.info.num_pieces());
+        }
+        Command::Create {
+            input,
+            output,
+            announce,
+            piece_length,
+        } => {
+            let t = Torrent::create(&input, announce, piece_length)?;
+            let dot_torrent = t.to_bytes()?;
+            tokio::fs::write(&output, dot_torrent)
+                .await
+                .context("write torrent file")?;
+            println!("Info Hash: {}", hex::encode(t.info_hash()));
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn flags_are_kebab_case_and_subcommands_snake_case() {
+        Args::command().debug_assert();
+        let parse = |args: &[&str]| Args::try_parse_from(["bittorrent"].iter().chain(args));
+
+        let args = parse(&[
+            "download",
+            "t.torrent",
+            "--verify-threads",
+            "2",
+            "--max-peers",
+            "4",
+            "--max-size",
+            "100",
+            "--block-size",
+            "1024",
+        ])
+        .unwrap();
+        let Command::Download {
+            verify_threads,
+            max_peers,
+            max_size,
+            block_size,
+            ..
+        } = args.command
+        else {
+            panic!("parsed {:?}", args.command);
+        };
+        assert_eq!(
+            (verify_threads, max_peers, max_size, block_size),
+            (Some(2), 4, Some(100), 1024)
+        );
+        assert!(parse(&["download", "t.torrent", "--max_peers", "4"]).is_err());
+
+        assert!(parse(&["download_piece", "-o", "p", "t.torrent", "0", "--no-verify"]).is_ok());
+        assert!(parse(&["download-piece", "-o", "p", "t.torrent", "0"]).is_err());
+        assert!(parse(&[
+            "create",
+            "dir",
+            "-o",
+            "t",
+            "--announce",
+            "a",
+            "--piece-length",
+            "16384"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn max_size_caps_the_torrent_length() {
+        assert!(check_max_size(1 << 30, None).is_ok());
+        assert!(check_max_size(1000, Some(1000)).is_ok());
+        let e = check_max_size(2048, Some(1024)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "torrent is 2.00 KiB (2048 bytes), more than the 1.00 KiB (1024 bytes) allowed by --max-size"
+        );
+    }
+
+    #[test]
+    fn peers_as_json() {
+        let peers: [SocketAddr; 2] = [
+            "10.0.0.1:6881".parse().unwrap(),
+            "[::1]:51413".parse().unwrap(),
+        ];
+        let json = peers_json(peers.into_iter()).to_string();
+        assert_eq!(
+            json,
+            r#"[{"ip":"10.0.0.1","port":6881},{"ip":"::1","port":51413}]"#
+        );
+        // and it reads back as the same peers
+        let parsed: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let addrs: Vec<SocketAddr> = parsed
+            .iter()
+            .map(|peer| {
+                let ip: std::net::IpAddr = peer["ip"].as_str().unwrap().parse().unwrap();
+                SocketAddr::new(ip, peer["port"].as_u64().unwrap() as u16)
+            })
+            .collect();
+        assert_eq!(addrs, peers);
+        assert_eq!(peers_json(std::iter::empty()).to_string(), "[]");
+    }
+}
@@ -0,0 +1,114 @@
+//! Fetching a torrent's info dictionary from peers (BEP 9), for when all we have is its info hash.
+//!
+//! Like PEX, `ut_metadata` rides on the extension protocol (BEP 10). The info dictionary is sent
+//! in pieces of 16 KiB, each as a bencoded header followed by the raw bytes of the piece.
+
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::peer::{Encryption, Peer};
+
+/// The extended message id we ask peers to send us `ut_metadata` messages with.
+pub(crate) const UT_METADATA: u8 = 2;
+
+/// How many bytes of the info dictionary go in each piece; all but the last are this long.
+pub(crate) const PIECE_SIZE: usize = 16 * 1024;
+
+/// How many peers we try fetching the info dictionary from at once.
+const CONCURRENT_PEERS: usize = 5;
+
+// `msg_type`s
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// The bencoded header of a `ut_metadata` message.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    msg_type: i64,
+    piece: i64,
+    /// The size of the whole info dictionary, in `data` messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/// A `ut_metadata` message from a peer.
+#[derive(Debug)]
+pub(crate) enum Reply<'a> {
+    Data {
+        piece: usize,
+        data: &'a [u8],
+    },
+    Reject {
+        piece: usize,
+    },
+    /// A request for one of our pieces (which we don't serve) or a message type we don't know.
+    Other,
+}
+
+/// The payload of a `ut_metadata` message asking for piece `piece`.
+pub(crate) fn request(piece: usize) -> Vec<u8> {
+    let header = Header {
+        msg_type: REQUEST,
+        piece: piece as i64,
+        total_size: None,
+    };
+    serde_bencode::to_bytes(&header).expect("ut_metadata headers always encode")
+}
+
+/// Parses the payload of a `ut_metadata` message.
+pub(crate) fn parse(payload: &[u8]) -> anyhow::Result<Reply<'_>> {
+    // serde_bencode won't stop at the end of the header, so find it ourselves
+    let header_len =
+        crate::torrent::value_len(payload).context("ut_metadata message has no valid header")?;
+    let (header, data) = payload.split_at(header_len);
+    let header: Header = crate::from_bencode(header).context("parse ut_metadata header")?;
+    let piece = usize::try_from(header.piece).context("ut_metadata piece is negative")?;
+    Ok(match header.msg_type {
+        DATA => Reply::Data { piece, data },
+        REJECT => Reply::Reject { piece },
+        _ => Reply::Other,
+    })
+}
+
+/// Fetches the info dictionary of the torrent with the given info hash from whichever of `peers`
+/// sends a copy that matches it first. Peers that say it's more than `max_size` bytes are skipped.
+pub(crate) async fn fetch(
+    info_hash: [u8; 20],
+    peers: &[SocketAddr],
+    encryption: Encryption,
+    max_size: usize,
+) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        !peers.is_empty(),
+        "no peers to fetch the torrent's metadata from"
+    );
+    let mut attempts = futures_util::stream::iter(peers.iter().copied())
+        .map(|peer_addr| async move {
+            let fetched = async {
+                let mut peer = Peer::connect_for_metadata(peer_addr, info_hash, encryption).await?;
+                let fetched = peer.fetch_metadata(info_hash, max_size).await;
+                peer.disconnect("done with metadata").await;
+                fetched
+            };
+            (peer_addr, fetched.await)
+        })
+        .buffer_unordered(CONCURRENT_PEERS);
+    while let Some((peer_addr, fetched)) = attempts.next().await {
+        match fetched {
+            Ok(info) => {
+                debug!(peer = %peer_addr, size = info.len(), "fetched torrent metadata");
+                return Ok(info);
+            }
+            Err(e) => warn!(peer = %peer_addr, "failed to fetch torrent metadata: {e:#}"),
+        }
+    }
+    anyhow::bail!(
+        "none of the {} peers could send the torrent's metadata",
+        peers.len()
+    )
+}
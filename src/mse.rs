@@ -0,0 +1,649 @@
+//! Message Stream Encryption (MSE, a.k.a. protocol encryption): a Diffie-Hellman key exchange
+//! followed by RC4, which hides BitTorrent traffic from ISPs that throttle it.
+//!
+//! This is obfuscation, not security: there's no authentication, so anyone in the middle can
+//! still read along.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use rand::{rngs::OsRng, Rng, RngCore};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use crate::peer::Encryption;
+
+/// The Diffie-Hellman prime, P.
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+/// The length of a public key (and of the shared secret): 768 bits.
+const KEY_LEN: usize = 96;
+
+/// The length of our private key: 160 bits.
+const PRIVATE_KEY_LEN: usize = 20;
+
+/// The most random padding either side may put after its public key.
+const MAX_PAD: usize = 512;
+
+/// The verification constant, which tells us where the encrypted part starts.
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// How long the other side may go quiet in the middle of the handshake.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The start of a plaintext BitTorrent handshake, which is how we tell one from an MSE handshake
+/// on incoming connections.
+const PLAINTEXT_START: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// A peer connection that's encrypted if the MSE handshake picked RC4, and passes data through
+/// untouched otherwise.
+pub(crate) struct Stream {
+    inner: TcpStream,
+    /// Decrypts what the peer sends, and encrypts what we send.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Data that arrived with the handshake, already decrypted.
+    read_ahead: Vec<u8>,
+    /// Data we've encrypted, but not yet handed to the socket.
+    write_buf: Vec<u8>,
+}
+
+impl Stream {
+    pub(crate) fn plaintext(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            ciphers: None,
+            read_ahead: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Hands everything in `write_buf` to the socket.
+    fn poll_drain(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let n = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((decrypt, _)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some((_, encrypt)) = &mut this.ciphers else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        // once encrypted, bytes have to go out exactly as they are, so they wait in `write_buf`
+        // rather than being encrypted again on a retry
+        let start = this.write_buf.len();
+        this.write_buf.extend_from_slice(buf);
+        encrypt.apply(&mut this.write_buf[start..]);
+        // push out what we can now; the rest goes on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Runs the MSE handshake on a connection we opened, for the torrent with `info_hash`.
+///
+/// We only ever offer RC4: with [`Encryption::Prefer`], falling back to plaintext is up to the
+/// caller, on a fresh connection.
+pub(crate) async fn connect(mut inner: TcpStream, info_hash: [u8; 20]) -> anyhow::Result<Stream> {
+    let private_key = random_bytes(PRIVATE_KEY_LEN);
+    let mut hello = public_key(&private_key).await?.to_vec();
+    hello.extend(random_bytes(random_pad_len()));
+    inner.write_all(&hello).await.context("send public key")?;
+
+    let mut received = Vec::new();
+    fill(&mut inner, &mut received, KEY_LEN).await?;
+    let their_key: [u8; KEY_LEN] = received[..KEY_LEN].try_into().expect("just filled");
+    received.drain(..KEY_LEN);
+    let secret = pow_mod(their_key, &private_key).await?;
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut request = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    request.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut offer = VC.to_vec();
+    offer.extend(CRYPTO_RC4.to_be_bytes());
+    // no padding, and no initial payload: the BitTorrent handshake follows once we're set up
+    offer.extend(0u16.to_be_bytes());
+    offer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    request.extend(offer);
+    inner
+        .write_all(&request)
+        .await
+        .context("send crypto offer")?;
+
+    // the peer's padding hides where its answer starts, but its encrypted VC gives it away
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    let start = sync(&mut inner, &mut received, &vc).await?;
+    received.drain(..start + vc.len());
+
+    fill(&mut inner, &mut received, 6).await?;
+    decrypt.apply(&mut received[..6]);
+    let select = u32::from_be_bytes(received[..4].try_into().expect("4 bytes"));
+    let pad_len = usize::from(u16::from_be_bytes([received[4], received[5]]));
+    received.drain(..6);
+    anyhow::ensure!(
+        select == CRYPTO_RC4,
+        "peer picked crypto method {select:#x}, which we didn't offer"
+    );
+    anyhow::ensure!(pad_len <= MAX_PAD, "peer sent {pad_len} bytes of padding");
+    fill(&mut inner, &mut received, pad_len).await?;
+    decrypt.apply(&mut received[..pad_len]);
+    received.drain(..pad_len);
+
+    // anything after the padding is already part of the encrypted stream
+    decrypt.apply(&mut received);
+    debug!("encrypted connection set up");
+    Ok(Stream {
+        inner,
+        ciphers: Some((decrypt, encrypt)),
+        read_ahead: received,
+        write_buf: Vec::new(),
+    })
+}
+
+/// Sets up a connection a peer opened to us, which may start with either a plaintext
+/// BitTorrent handshake or an MSE handshake for the torrent with `info_hash`.
+pub(crate) async fn accept(
+    mut inner: TcpStream,
+    info_hash: [u8; 20],
+    encryption: Encryption,
+) -> anyhow::Result<Stream> {
+    let mut received = Vec::new();
+    // a plaintext peer may well have sent its whole handshake already
+    fill(&mut inner, &mut received, PLAINTEXT_START.len()).await?;
+    if received.starts_with(PLAINTEXT_START) {
+        anyhow::ensure!(
+            encryption != Encryption::Require,
+            "peer connected without encryption"
+        );
+        let mut stream = Stream::plaintext(inner);
+        stream.read_ahead = received;
+        return Ok(stream);
+    }
+    anyhow::ensure!(
+        encryption != Encryption::Disable,
+        "peer didn't start with a BitTorrent handshake"
+    );
+
+    fill(&mut inner, &mut received, KEY_LEN).await?;
+    let their_key: [u8; KEY_LEN] = received[..KEY_LEN].try_into().expect("just filled");
+    received.drain(..KEY_LEN);
+    let private_key = random_bytes(PRIVATE_KEY_LEN);
+    let mut hello = public_key(&private_key).await?.to_vec();
+    hello.extend(random_bytes(random_pad_len()));
+    inner.write_all(&hello).await.context("send public key")?;
+    let secret = pow_mod(their_key, &private_key).await?;
+
+    let req1 = hash(&[b"req1", &secret]);
+    let start = sync(&mut inner, &mut received, &req1).await?;
+    received.drain(..start + req1.len());
+    fill(&mut inner, &mut received, 20).await?;
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let expected: Vec<u8> = req2.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+    anyhow::ensure!(
+        received[..20] == expected,
+        "peer asked for a torrent we are not downloading"
+    );
+    received.drain(..20);
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    fill(&mut inner, &mut received, VC.len() + 6).await?;
+    decrypt.apply(&mut received[..VC.len() + 6]);
+    anyhow::ensure!(
+        received[..VC.len()] == VC,
+        "peer sent a bad verification constant"
+    );
+    received.drain(..VC.len());
+    let provide = u32::from_be_bytes(received[..4].try_into().expect("4 bytes"));
+    let pad_len = usize::from(u16::from_be_bytes([received[4], received[5]]));
+    received.drain(..6);
+    anyhow::ensure!(pad_len <= MAX_PAD, "peer sent {pad_len} bytes of padding");
+    fill(&mut inner, &mut received, pad_len + 2).await?;
+    decrypt.apply(&mut received[..pad_len + 2]);
+    let ia_len = usize::from(u16::from_be_bytes([
+        received[pad_len],
+        received[pad_len + 1],
+    ]));
+    received.drain(..pad_len + 2);
+    fill(&mut inner, &mut received, ia_len).await?;
+    // the initial payload is always encrypted, whatever we pick for the rest
+    decrypt.apply(&mut received[..ia_len]);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && encryption != Encryption::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!("peer offered no crypto method we accept ({provide:#x})");
+    };
+    let mut answer = VC.to_vec();
+    answer.extend(select.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    inner
+        .write_all(&answer)
+        .await
+        .context("send crypto choice")?;
+
+    if select == CRYPTO_RC4 {
+        decrypt.apply(&mut received[ia_len..]);
+        debug!("accepted encrypted connection");
+        Ok(Stream {
+            inner,
+            ciphers: Some((decrypt, encrypt)),
+            read_ahead: received,
+            write_buf: Vec::new(),
+        })
+    } else {
+        debug!("accepted obfuscated plaintext connection");
+        let mut stream = Stream::plaintext(inner);
+        stream.read_ahead = received;
+        Ok(stream)
+    }
+}
+
+/// Reads from `inner` until `received` holds at least `len` bytes.
+async fn fill(inner: &mut TcpStream, received: &mut Vec<u8>, len: usize) -> anyhow::Result<()> {
+    while received.len() < len {
+        let n = tokio::time::timeout(IDLE_TIMEOUT, inner.read_buf(received))
+            .await
+            .context("peer went idle during the encryption handshake")?
+            .context("read encryption handshake")?;
+        anyhow::ensure!(
+            n != 0,
+            "peer closed the connection during the encryption handshake"
+        );
+    }
+    Ok(())
+}
+
+/// Reads until `marker` turns up within the first [`MAX_PAD`] bytes (plus the marker itself), and
+/// returns where it starts.
+async fn sync(
+    inner: &mut TcpStream,
+    received: &mut Vec<u8>,
+    marker: &[u8],
+) -> anyhow::Result<usize> {
+    loop {
+        if let Some(start) = received
+            .windows(marker.len())
+            .position(|window| window == marker)
+        {
+            return Ok(start);
+        }
+        anyhow::ensure!(
+            received.len() < MAX_PAD + marker.len(),
+            "peer's encryption handshake never got to the point"
+        );
+        let wanted = received.len() + 1;
+        fill(inner, received, wanted).await?;
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Random bytes from the OS's cryptographically secure generator, for private keys and padding.
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn random_pad_len() -> usize {
+    OsRng.gen_range(0..=MAX_PAD)
+}
+
+/// Our public key, 2^private_key mod P.
+async fn public_key(private_key: &[u8]) -> anyhow::Result<[u8; KEY_LEN]> {
+    let mut two = [0; KEY_LEN];
+    two[KEY_LEN - 1] = 2;
+    pow_mod(two, private_key).await
+}
+
+/// base^exponent mod P, which is also how we get the secret we share with the peer from its
+/// public key and our private key.
+///
+/// This takes long enough that it runs off the async runtime.
+async fn pow_mod(base: [u8; KEY_LEN], exponent: &[u8]) -> anyhow::Result<[u8; KEY_LEN]> {
+    let exponent = exponent.to_vec();
+    tokio::task::spawn_blocking(move || BigNum::from_bytes(&base).pow_mod(&exponent).to_bytes())
+        .await
+        .context("compute encryption key")
+}
+
+/// Just enough of a 768-bit unsigned integer for modular exponentiation mod [`PRIME`].
+///
+/// The limbs are little-endian. Multiplication is shift-and-add, which is slow but only runs a
+/// few hundred times per handshake.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct BigNum([u64; KEY_LEN / 8]);
+
+impl BigNum {
+    fn from_bytes(bytes: &[u8; KEY_LEN]) -> Self {
+        let mut limbs = [0; KEY_LEN / 8];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().expect("8 bytes"));
+        }
+        let mut n = Self(limbs);
+        // only the peer's key can be out of range, and a key that is isn't worth rejecting
+        while n >= Self::prime() {
+            n = n.wrapping_sub(&Self::prime());
+        }
+        n
+    }
+
+    fn to_bytes(self) -> [u8; KEY_LEN] {
+        let mut bytes = [0; KEY_LEN];
+        for (chunk, limb) in bytes.rchunks_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn prime() -> Self {
+        let mut limbs = [0; KEY_LEN / 8];
+        for (limb, chunk) in limbs.iter_mut().zip(PRIME.rchunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().expect("8 bytes"));
+        }
+        Self(limbs)
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0[i / 64] >> (i % 64) & 1 == 1
+    }
+
+    fn wrapping_sub(&self, other: &Self) -> Self {
+        let mut out = [0; KEY_LEN / 8];
+        let mut borrow = false;
+        for (o, (a, b)) in out.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            let (d, b1) = a.overflowing_sub(*b);
+            let (d, b2) = d.overflowing_sub(u64::from(borrow));
+            *o = d;
+            borrow = b1 || b2;
+        }
+        Self(out)
+    }
+
+    /// (self + other) mod P, for self and other both below P.
+    fn add_mod(&self, other: &Self) -> Self {
+        let mut out = [0; KEY_LEN / 8];
+        let mut carry = false;
+        for (o, (a, b)) in out.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            let (s, c1) = a.overflowing_add(*b);
+            let (s, c2) = s.overflowing_add(u64::from(carry));
+            *o = s;
+            carry = c1 || c2;
+        }
+        let sum = Self(out);
+        if carry || sum >= Self::prime() {
+            sum.wrapping_sub(&Self::prime())
+        } else {
+            sum
+        }
+    }
+
+    fn mul_mod(&self, other: &Self) -> Self {
+        let mut out = Self([0; KEY_LEN / 8]);
+        for i in (0..KEY_LEN * 8).rev() {
+            out = out.add_mod(&out);
+            if other.bit(i) {
+                out = out.add_mod(self);
+            }
+        }
+        out
+    }
+
+    /// self^exponent mod P, with `exponent` big-endian.
+    fn pow_mod(&self, exponent: &[u8]) -> Self {
+        let mut one = [0; KEY_LEN / 8];
+        one[0] = 1;
+        let mut out = Self(one);
+        for byte in exponent {
+            for i in (0..8).rev() {
+                out = out.mul_mod(&out);
+                if byte >> i & 1 == 1 {
+                    out = out.mul_mod(self);
+                }
+            }
+        }
+        out
+    }
+}
+
+impl PartialOrd for BigNum {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigNum {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+/// The RC4 stream cipher, with the first 1024 bytes of keystream thrown away as MSE requires.
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::without_discard(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /// Plain RC4, keystream and all.
+    fn without_discard(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, usize::from(j));
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts (it's the same thing) `data` in place.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[usize::from(self.i)]);
+            self.s.swap(usize::from(self.i), usize::from(self.j));
+            let k = self.s[usize::from(
+                self.s[usize::from(self.i)].wrapping_add(self.s[usize::from(self.j)]),
+            )];
+            *byte ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn rc4_known_answers() {
+        for (key, plaintext, ciphertext) in [
+            ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+            ("Wiki", "pedia", "1021bf0420"),
+            ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ] {
+            let mut data = plaintext.as_bytes().to_vec();
+            Rc4::without_discard(key.as_bytes()).apply(&mut data);
+            assert_eq!(hex::encode(&data), ciphertext, "key {key:?}");
+        }
+    }
+
+    #[test]
+    fn rc4_discards_first_kilobyte() {
+        let mut keystream = [0; 1024 + 16];
+        Rc4::without_discard(b"Key").apply(&mut keystream);
+        let mut discarded = [0; 16];
+        Rc4::new(b"Key").apply(&mut discarded);
+        assert_eq!(discarded, keystream[1024..]);
+    }
+
+    #[tokio::test]
+    async fn pow_mod_known_results() {
+        let private_key: Vec<u8> = (1..=20).collect();
+        assert_eq!(
+            hex::encode(public_key(&private_key).await.unwrap()),
+            "96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556b0918db2b4c658e02a87f7\
+             e5fb14b18a553e084cbf3dad2d30f16596ccb982d406258c61b30c5c1dae2ddc60bdbd48d79896312aad63\
+             238c39e1a633821eb693"
+        );
+
+        // both sides end up with the same secret
+        let other_key: Vec<u8> = (21..=40).collect();
+        let ours = pow_mod(public_key(&other_key).await.unwrap(), &private_key);
+        let theirs = pow_mod(public_key(&private_key).await.unwrap(), &other_key);
+        let (ours, theirs) = (ours.await.unwrap(), theirs.await.unwrap());
+        assert_eq!(ours, theirs);
+        assert_eq!(
+            hex::encode(ours),
+            "994aac6c359990cf4f678a1742b587eb1a5248ec7fcc0d0bcfcb12d2461bc1fe25417b70869697d9ca8848\
+             32f1c5f2a2fd3318c22a5a6ba170d36aac91405457c1e8137b1534a776865ed353f12422ff6afc58435f8b\
+             d443f61dd051a37bcdeb"
+        );
+    }
+
+    /// A TCP connection over loopback, as (our end, their end).
+    async fn loopback() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ours, theirs) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (ours.unwrap(), theirs.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn encrypted_round_trip() {
+        let info_hash = [7; 20];
+        let (ours, theirs) = loopback().await;
+        let (ours, theirs) = tokio::join!(
+            connect(ours, info_hash),
+            accept(theirs, info_hash, Encryption::Require)
+        );
+        let (mut ours, mut theirs) = (ours.unwrap(), theirs.unwrap());
+        assert!(ours.ciphers.is_some() && theirs.ciphers.is_some());
+
+        ours.write_all(b"hello from us").await.unwrap();
+        ours.flush().await.unwrap();
+        let mut buf = [0; 13];
+        theirs.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from us");
+
+        theirs.write_all(b"and back").await.unwrap();
+        theirs.flush().await.unwrap();
+        let mut buf = [0; 8];
+        ours.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"and back");
+    }
+
+    #[tokio::test]
+    async fn wrong_info_hash_is_refused() {
+        let (ours, theirs) = loopback().await;
+        let (_, theirs) = tokio::join!(
+            connect(ours, [1; 20]),
+            accept(theirs, [2; 20], Encryption::Prefer)
+        );
+        assert!(theirs.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_peer_is_let_through_unless_required() {
+        for (encryption, allowed) in [(Encryption::Prefer, true), (Encryption::Require, false)] {
+            let (mut ours, theirs) = loopback().await;
+            ours.write_all(PLAINTEXT_START).await.unwrap();
+            let stream = accept(theirs, [0; 20], encryption).await;
+            assert_eq!(stream.is_ok(), allowed, "{encryption}");
+        }
+    }
+}
@@ -1,197 +1,1253 @@
-use crate::BLOCK_MAX;
+use crate::{
+    download::Report, metadata, mse, pex, piece, scheduler::Scheduler, storage::Storage, Error,
+};
 use anyhow::Context;
 use bytes::{Buf, BufMut};
-use futures_util::{SinkExt, StreamExt};
-use std::{mem, net::SocketAddrV4};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use sha1::Digest;
+use std::{
+    collections::VecDeque,
+    fmt, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
 };
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed},
+    sync::CancellationToken,
+};
+use tracing::{debug, instrument, trace, warn};
 
-pub(crate) struct Peer {
-    #[allow(dead_code)]
-    addr: SocketAddrV4,
-    stream: Framed<TcpStream, MessageFramer>,
+pub struct Peer {
+    addr: SocketAddr,
+    stream: Framed<mse::Stream, MessageFramer>,
     bitfield: Bitfield,
+    /// How many pieces the torrent has, or 0 if we don't know yet.
+    num_pieces: usize,
     choked: bool,
+    received: Rate,
+    /// Whether the peer speaks the extension protocol (BEP 10).
+    extensions: bool,
+    /// The extended message id the peer wants `ut_pex` messages sent with, if it does PEX.
+    ut_pex: Option<u8>,
+    /// The extended message id the peer wants `ut_metadata` messages sent with, if it can send us
+    /// the torrent's info dictionary.
+    ut_metadata: Option<u8>,
+    /// How big the peer says the info dictionary is, if it told us (`metadata_size`).
+    metadata_size: Option<usize>,
+    /// How many of our requests the peer will queue up, if it told us (`reqq`).
+    reqq: Option<usize>,
+    /// Set once we've offered the peer PEX.
+    pex: Option<pex::Exchange>,
+    /// Whether we both speak the fast extension (BEP 6).
+    fast: bool,
+    /// Whether we've told the peer we're interested in its pieces.
+    interested: bool,
+    /// Set once the peer has sent any BEP 3 message, after which it's too late for it to send its
+    /// bitfield (or have all, or have none).
+    bitfield_closed: bool,
+}
+
+/// Whether to encrypt peer connections with Message Stream Encryption (MSE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    /// Only talk to peers over encrypted connections.
+    Require,
+    /// Try encrypting first, but fall back to plaintext for peers that can't.
+    Prefer,
+    /// Never encrypt, and turn away peers that want to.
+    #[default]
+    Disable,
+}
+
+impl FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "require" => Ok(Self::Require),
+            "prefer" => Ok(Self::Prefer),
+            "disable" => Ok(Self::Disable),
+            _ => anyhow::bail!("encryption must be one of require, prefer or disable, not {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Require => "require",
+            Self::Prefer => "prefer",
+            Self::Disable => "disable",
+        })
+    }
 }
 
 impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr)
+    /// Connects and handshakes with a peer for the torrent with the given info hash, which has
+    /// `num_pieces` pieces.
+    #[instrument(skip(info_hash, num_pieces))]
+    pub async fn new(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        encryption: Encryption,
+    ) -> Result<Self, Error> {
+        Self::connect(peer_addr, info_hash, num_pieces, encryption)
             .await
-            .context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+            .map_err(|e| match e.downcast::<Error>() {
+                Ok(e) => e,
+                Err(source) => Error::HandshakeFailed {
+                    peer: peer_addr,
+                    source,
+                },
+            })
+    }
+
+    async fn connect(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        let (peer, reserved) = Self::open(peer_addr, info_hash, encryption).await?;
+        Self::handshaken(peer_addr, peer, num_pieces, reserved).await
+    }
+
+    /// Connects to a peer only to [fetch the torrent's metadata](Self::fetch_metadata) from it,
+    /// so we don't wait for a bitfield we couldn't check without knowing how many pieces there
+    /// are.
+    pub(crate) async fn connect_for_metadata(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        let (peer, reserved) = Self::open(peer_addr, info_hash, encryption).await?;
+        Ok(Self::from_stream(peer_addr, peer, reserved, 0))
+    }
+
+    /// Connects and handshakes with a peer, returning the connection and the `reserved` bytes of
+    /// the peer's handshake.
+    async fn open(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        encryption: Encryption,
+    ) -> anyhow::Result<(mse::Stream, [u8; 8])> {
+        let mut peer = if encryption == Encryption::Disable {
+            mse::Stream::plaintext(connect_tcp(peer_addr).await?)
+        } else {
+            let encrypted = mse::connect(connect_tcp(peer_addr).await?, info_hash).await;
+            match encrypted {
+                Ok(peer) => peer,
+                Err(e) if encryption == Encryption::Prefer => {
+                    // a peer that doesn't do MSE just hangs up on us, so start over
+                    debug!("encryption handshake failed, so trying plaintext: {e:#}");
+                    mse::Stream::plaintext(connect_tcp(peer_addr).await?)
+                }
+                Err(e) => return Err(e.context("set up encrypted connection")),
+            }
+        };
+        let mut handshake = Handshake::new(info_hash, *crate::PEER_ID);
+        handshake.reserved = OUR_RESERVED;
         {
             let handshake_bytes = handshake.as_bytes_mut();
             peer.write_all(handshake_bytes)
                 .await
                 .context("write handshake")?;
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
+            peer.flush().await.context("write handshake")?;
+            read_handshake(&mut peer, handshake_bytes, HANDSHAKE_IDLE_TIMEOUT).await?;
         }
         anyhow::ensure!(handshake.length == 19);
         anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bitfield = peer
-            // method from future_util streamExt
-            .next()
-            .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+        debug!(
+            peer_id = hex::encode(handshake.peer_id()),
+            "handshake complete"
+        );
+        Ok((peer, handshake.reserved))
+    }
+
+    /// Sets up a connection that a peer opened to us, so we're the receiving side of the
+    /// handshake: they go first, and we only answer if they're after the torrent we have.
+    #[instrument(skip_all, fields(peer = ?peer.peer_addr().ok()))]
+    pub async fn accept(
+        peer: TcpStream,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        let (peer_addr, peer, reserved) = accept_handshake(peer, info_hash, encryption).await?;
+        Self::handshaken(peer_addr, peer, num_pieces, reserved).await
+    }
+
+    /// Like [`accept`](Self::accept), but for a connection we'll only ever [`serve`](Self::serve):
+    /// we don't wait for the peer's bitfield, since it's waiting for ours.
+    #[instrument(skip_all, fields(peer = ?peer.peer_addr().ok()))]
+    pub(crate) async fn accept_to_seed(
+        peer: TcpStream,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        let (peer_addr, peer, reserved) = accept_handshake(peer, info_hash, encryption).await?;
+        Ok(Self::from_stream(peer_addr, peer, reserved, num_pieces))
+    }
+
+    /// Picks up a connection after the handshake, where the peer's handshake had the given
+    /// `reserved` bytes.
+    async fn handshaken(
+        peer_addr: SocketAddr,
+        peer: mse::Stream,
+        num_pieces: usize,
+        reserved: [u8; 8],
+    ) -> anyhow::Result<Self> {
+        let mut peer = Self::from_stream(peer_addr, peer, reserved, num_pieces);
 
-        Ok(Self {
+        // the bitfield is optional: a peer with no pieces may skip it, and may then have nothing
+        // to say to us at all for a while. Extension messages (like the extended handshake) may
+        // come before it, though.
+        loop {
+            let msg = match tokio::time::timeout(BITFIELD_TIMEOUT, peer.stream.next()).await {
+                Err(_) => {
+                    debug!("peer sent no bitfield, so assuming it has no pieces");
+                    break;
+                }
+                Ok(None) => return Err(Error::PeerDisconnected { peer: peer_addr }.into()),
+                Ok(Some(msg)) => msg.context("peer message was invalid")?,
+            };
+            let tag = msg.tag;
+            peer.idle_message(msg)?;
+            if tag != MessageTag::Extended {
+                break;
+            }
+        }
+        Ok(peer)
+    }
+
+    /// Wraps a connection whose handshake is done, where the peer's handshake had the given
+    /// `reserved` bytes, for a torrent with `num_pieces` pieces.
+    fn from_stream(
+        peer_addr: SocketAddr,
+        peer: mse::Stream,
+        reserved: [u8; 8],
+        num_pieces: usize,
+    ) -> Self {
+        let extensions = reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0;
+        // we always set the bit, so it's up to the peer
+        let fast = reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0;
+        Self {
             addr: peer_addr,
-            stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
+            stream: tokio_util::codec::Framed::new(peer, MessageFramer),
+            bitfield: Bitfield::empty(num_pieces),
+            num_pieces,
             choked: true,
-        })
+            received: Rate::default(),
+            extensions,
+            ut_pex: None,
+            ut_metadata: None,
+            metadata_size: None,
+            reqq: None,
+            pex: None,
+            fast,
+            interested: false,
+            bitfield_closed: false,
+        }
     }
 
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        self.bitfield.has_piece(piece_i)
+    /// Fails unless we negotiated the fast extension, which the message with `tag` is part of.
+    fn fast_only(&self, tag: MessageTag) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.fast,
+            "peer sent {tag:?} without negotiating the fast extension"
+        );
+        Ok(())
     }
 
-    pub(crate) async fn participate(
+    /// Notes that a message with `tag` arrived, and checks it's not a bitfield that comes too
+    /// late: the peer may only send one before any other BEP 3 message (but extension messages
+    /// may come first).
+    fn received(&mut self, tag: MessageTag) -> anyhow::Result<()> {
+        match tag {
+            MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                if self.bitfield_closed {
+                    warn!(?tag, "peer sent its pieces after other messages");
+                    anyhow::bail!("peer sent {tag:?} after other messages");
+                }
+            }
+            MessageTag::Extended
+            | MessageTag::Suggest
+            | MessageTag::RejectRequest
+            | MessageTag::AllowedFast => return Ok(()),
+            MessageTag::Choke
+            | MessageTag::Unchoke
+            | MessageTag::Interested
+            | MessageTag::NotInterested
+            | MessageTag::Have
+            | MessageTag::Request
+            | MessageTag::Piece
+            | MessageTag::Cancel => {}
+        }
+        self.bitfield_closed = true;
+        Ok(())
+    }
+
+    /// Takes in a bitfield, have all or have none message, which tells us every piece the peer
+    /// has. Check it's not too late for one with [`received`](Self::received) first.
+    fn set_pieces(&mut self, msg: Message) -> anyhow::Result<()> {
+        match msg.tag {
+            MessageTag::Bitfield => {
+                let bitfield = Bitfield::from_payload(msg.payload);
+                bitfield
+                    .validate(self.num_pieces)
+                    .context("peer sent a malformed bitfield")?;
+                self.bitfield = bitfield;
+            }
+            MessageTag::HaveAll => {
+                self.fast_only(msg.tag)?;
+                self.bitfield = Bitfield::full(self.num_pieces);
+            }
+            MessageTag::HaveNone => {
+                self.fast_only(msg.tag)?;
+                self.bitfield = Bitfield::empty(self.num_pieces);
+            }
+            tag => unreachable!("{tag:?} doesn't say which pieces the peer has"),
+        }
+        Ok(())
+    }
+
+    /// Records that the peer just told us it has another piece.
+    fn have(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let piece_i: [u8; 4] = payload
+            .try_into()
+            .context("have message payload should be a 4 byte piece index")?;
+        let piece_i = u32::from_be_bytes(piece_i) as usize;
+        anyhow::ensure!(
+            piece_i < self.num_pieces,
+            "peer has piece {piece_i}, but there are only {} pieces",
+            self.num_pieces
+        );
+        self.bitfield.set_piece(piece_i);
+        Ok(())
+    }
+
+    /// Offers the peer PEX, if it speaks the extension protocol, and starts passing on the peers
+    /// it tells us about to `swarm`.
+    ///
+    /// Never call this for private torrents.
+    pub(crate) async fn enable_pex(&mut self, swarm: pex::Swarm) -> anyhow::Result<()> {
+        if !self.extensions {
+            return Ok(());
+        }
+        let handshake = pex::ExtendedHandshake {
+            m: [("ut_pex".to_string(), i64::from(pex::UT_PEX))].into(),
+            ..Default::default()
+        };
+        self.send_extended_handshake(&handshake).await?;
+        self.pex = Some(pex::Exchange::new(swarm));
+        Ok(())
+    }
+
+    async fn send_extended_handshake(
         &mut self,
-        piece_i: usize,
-        piece_size: usize,
-        nblocks: usize,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        handshake: &pex::ExtendedHandshake,
     ) -> anyhow::Result<()> {
-        anyhow::ensure!(self.bitfield.has_piece(piece_i));
-
+        let mut payload = vec![EXTENDED_HANDSHAKE];
+        payload.extend(serde_bencode::to_bytes(handshake).context("encode extended handshake")?);
         self.stream
             .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
+                tag: MessageTag::Extended,
+                payload,
             })
             .await
-            .context("send interested message")?;
-
-        // TODO: timeout, error, and return block to submit if .next() timed out
-        'task: loop {
-            while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
-                        self.choked = false;
-                        assert!(unchoke.payload.is_empty());
+            .context("send extended handshake")
+    }
+
+    /// Downloads the torrent's info dictionary from the peer (BEP 9), one piece at a time, and
+    /// checks it against `info_hash`.
+    ///
+    /// If the peer says it's more than `max_size` bytes, we give up before allocating any room
+    /// for it, so a peer can't have us allocate whatever it likes.
+    pub(crate) async fn fetch_metadata(
+        &mut self,
+        info_hash: [u8; 20],
+        max_size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(self.extensions, "peer doesn't speak the extension protocol");
+        let handshake = pex::ExtendedHandshake {
+            m: [("ut_metadata".to_string(), i64::from(metadata::UT_METADATA))].into(),
+            ..Default::default()
+        };
+        self.send_extended_handshake(&handshake).await?;
+
+        // the peer's extended handshake says how to ask for the metadata, and how big it is
+        loop {
+            let msg = self.next_metadata_message().await?;
+            if msg.tag == MessageTag::Extended {
+                self.extended(&msg.payload);
+                if msg.payload.first() == Some(&EXTENDED_HANDSHAKE) {
+                    break;
+                }
+            }
+        }
+        let id = self
+            .ut_metadata
+            .context("peer doesn't offer the torrent's metadata")?;
+        let size = self
+            .metadata_size
+            .context("peer didn't say how big the metadata is")?;
+        anyhow::ensure!(size > 0, "peer says the metadata is empty");
+        anyhow::ensure!(
+            size <= max_size,
+            "peer says the metadata is {}, more than the {} we allow",
+            crate::human_size(size),
+            crate::human_size(max_size)
+        );
+
+        let mut info = vec![0; size];
+        for (piece_i, piece) in info.chunks_mut(metadata::PIECE_SIZE).enumerate() {
+            let mut payload = vec![id];
+            payload.extend(metadata::request(piece_i));
+            self.stream
+                .send(Message {
+                    tag: MessageTag::Extended,
+                    payload,
+                })
+                .await
+                .with_context(|| format!("send request for metadata piece {piece_i}"))?;
+            loop {
+                let msg = self.next_metadata_message().await?;
+                // we're only here for the metadata
+                if msg.tag != MessageTag::Extended {
+                    continue;
+                }
+                let Some((&metadata::UT_METADATA, payload)) = msg.payload.split_first() else {
+                    self.extended(&msg.payload);
+                    continue;
+                };
+                match metadata::parse(payload)? {
+                    metadata::Reply::Data { piece: i, data } if i == piece_i => {
+                        anyhow::ensure!(
+                            data.len() == piece.len(),
+                            "peer sent {} bytes of metadata piece {piece_i}, expected {}",
+                            data.len(),
+                            piece.len()
+                        );
+                        piece.copy_from_slice(data);
                         break;
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+                    metadata::Reply::Reject { piece: i } if i == piece_i => {
+                        anyhow::bail!("peer rejected our request for metadata piece {piece_i}");
                     }
-                    MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                    // not an answer to the request we have outstanding
+                    _ => {}
+                }
+            }
+        }
+        anyhow::ensure!(
+            <[u8; 20]>::from(sha1::Sha1::digest(&info)) == info_hash,
+            "peer sent metadata that doesn't match the info hash"
+        );
+        Ok(info)
+    }
+
+    /// The next message from a peer we're fetching metadata from.
+    async fn next_metadata_message(&mut self) -> anyhow::Result<Message> {
+        tokio::time::timeout(BLOCK_TIMEOUT, self.stream.next())
+            .await
+            .context("peer stopped sending metadata")?
+            .ok_or(Error::PeerDisconnected { peer: self.addr })
+            .context("wait for metadata")?
+            .context("peer message was invalid")
+    }
+
+    /// Handles an extension protocol message.
+    ///
+    /// Extensions are optional, so a peer that gets one wrong is merely ignored.
+    fn extended(&mut self, payload: &[u8]) {
+        let Some((&id, payload)) = payload.split_first() else {
+            warn!("peer sent an empty extended message");
+            return;
+        };
+        match id {
+            EXTENDED_HANDSHAKE => match crate::from_bencode::<pex::ExtendedHandshake>(payload) {
+                Ok(handshake) => {
+                    let id = |name: &str| {
+                        handshake
+                            .m
+                            .get(name)
+                            .and_then(|&id| u8::try_from(id).ok())
+                            .filter(|&id| id != 0)
+                    };
+                    self.ut_pex = id("ut_pex");
+                    self.ut_metadata = id("ut_metadata");
+                    self.metadata_size = handshake
+                        .metadata_size
+                        .and_then(|size| usize::try_from(size).ok());
+                    self.reqq = handshake
+                        .reqq
+                        .and_then(|reqq| usize::try_from(reqq).ok())
+                        .filter(|&reqq| reqq != 0);
+                    debug!(
+                        ut_pex = ?self.ut_pex,
+                        ut_metadata = ?self.ut_metadata,
+                        reqq = ?self.reqq,
+                        "got extended handshake"
+                    );
+                }
+                Err(e) => warn!("peer sent an invalid extended handshake: {e}"),
+            },
+            pex::UT_PEX => {
+                // we only ever asked for PEX if it's allowed for this torrent
+                if let Some(pex) = &self.pex {
+                    if let Err(e) = pex.receive(payload) {
+                        warn!("{e:#}");
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                }
+            }
+            metadata::UT_METADATA => {
+                trace!("ignoring ut_metadata message, since we aren't fetching metadata");
+            }
+            id => trace!(id, "ignoring unknown extended message"),
+        }
+    }
+
+    /// Tells the peer about the peers we've connected to since we last told it, if it's time.
+    async fn send_pex(&mut self) -> anyhow::Result<()> {
+        let (Some(id), Some(pex)) = (self.ut_pex, &mut self.pex) else {
+            return Ok(());
+        };
+        let Some(message) = pex.next_message(self.addr, Instant::now()) else {
+            return Ok(());
+        };
+        let mut payload = vec![id];
+        payload.extend(message);
+        self.stream
+            .send(Message {
+                tag: MessageTag::Extended,
+                payload,
+            })
+            .await
+            .context("send ut_pex message")
+    }
+
+    /// Closes the connection cleanly: anything we still have queued goes out, and then the peer
+    /// sees our side shut down rather than a reset.
+    pub async fn disconnect(mut self, reason: &str) {
+        debug!(peer = %self.addr, reason, "disconnecting");
+        match tokio::time::timeout(DISCONNECT_TIMEOUT, self.stream.close()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!(peer = %self.addr, "failed to close connection cleanly: {e:#}"),
+            Err(_) => debug!(peer = %self.addr, "peer took too long to take our last messages"),
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+        self.bitfield.has_piece(piece_i)
+    }
+
+    /// How fast this peer has been sending us blocks lately, in bytes per second.
+    pub(crate) fn download_rate(&self) -> f64 {
+        self.received.per_second(Instant::now())
+    }
+
+    /// Downloads whole pieces from this peer until there is no work left that it can help with.
+    ///
+    /// Pieces are taken from `work` one at a time, and every piece we take is handed back through
+    /// `reports` (see [`Report`] for what each message means), so no piece is ever lost when a
+    /// peer chokes us, misbehaves, or goes away.
+    #[instrument(skip_all, fields(peer = %self.addr))]
+    pub(crate) async fn participate(
+        &mut self,
+        work: &Scheduler,
+        reports: mpsc::Sender<Report>,
+        pipeline_depth: usize,
+        block_size: usize,
+        block_timeout: Duration,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<()> {
+        // our pieces count towards how rare each piece is for as long as we're participating
+        let mut pieces = work.join();
+        loop {
+            pieces.update(&self.bitfield);
+            self.send_pex().await?;
+            let wanted = work.wants(|piece_i| self.bitfield.has_piece(piece_i));
+            self.set_interested(wanted).await?;
+
+            // keep reading the peer's messages while we wait: it may unchoke or choke us, or tell
+            // us about a piece that makes it worth talking to again
+            let has = |piece_i| self.bitfield.has_piece(piece_i);
+            let piece = tokio::select! {
+                _ = cancel.cancelled() => break,
+                piece = work.take(has), if !self.choked => match piece {
+                    Some(piece) => piece,
+                    // every piece has been downloaded
+                    None => break,
+                },
+                more = work.wait_for(has), if self.choked && !wanted => {
+                    if !more {
+                        break;
                     }
+                    continue;
+                }
+                msg = self.stream.next() => {
+                    let msg = msg
+                        .ok_or(Error::PeerDisconnected { peer: self.addr })
+                        .context("wait for work")?
+                        .context("peer message was invalid")?;
+                    self.idle_message(msg)?;
+                    continue;
                 }
+            };
+            if let Err(e) = self.set_interested(true).await {
+                work.release(piece.index());
+                let _ = reports.send(Report::Abandoned { piece }).await;
+                return Err(e);
             }
-            let Ok(block) = tasks.recv().await else {
-                break;
+
+            let downloaded = tokio::select! {
+                // a peer that lost an end game race gets to cancel its requests, even though the
+                // download is over by the time it notices
+                biased;
+                downloaded = self.download_piece(
+                    work,
+                    &piece,
+                    pipeline_depth,
+                    block_size,
+                    block_timeout,
+                ) => downloaded,
+                _ = cancel.cancelled() => {
+                    work.release(piece.index());
+                    let _ = reports.send(Report::Abandoned { piece }).await;
+                    break;
+                }
             };
+            work.release(piece.index());
+            let report = match downloaded {
+                Ok(Some(bytes)) => {
+                    debug!(
+                        piece = piece.index(),
+                        rate = self.download_rate(),
+                        "downloaded piece"
+                    );
+                    Report::Downloaded {
+                        peer: self.addr,
+                        piece,
+                        bytes,
+                    }
+                }
+                Ok(None) => Report::Abandoned { piece },
+                Err(e) => {
+                    // hand the piece back before we give up on this peer
+                    let _ = reports.send(Report::Abandoned { piece }).await;
+                    return Err(e);
+                }
+            };
+            if reports.send(report).await.is_err() {
+                // the download is over
+                break;
+            }
+        }
 
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
-                } else {
-                    md
+        Ok(())
+    }
+
+    /// Serves the peer every block it asks for out of `storage`, which holds all of the torrent's
+    /// pieces, until it hangs up or `cancel` fires. Every byte sent is added to `uploaded`.
+    #[instrument(skip_all, fields(peer = %self.addr))]
+    pub(crate) async fn serve(
+        &mut self,
+        storage: &impl Storage,
+        uploaded: &AtomicUsize,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<()> {
+        self.stream
+            .send(Message::bitfield(Bitfield::full(self.num_pieces)))
+            .await
+            .context("send bitfield")?;
+        // we have bandwidth for everyone, so anyone who asks gets unchoked
+        let mut choking = true;
+        // requests we haven't answered yet, as their payloads, oldest first
+        let mut queued: VecDeque<Vec<u8>> = VecDeque::new();
+        loop {
+            if cancel.is_cancelled() {
+                return Ok(());
+            }
+            // take in whatever the peer has already sent before answering its next request, so
+            // that a cancel right behind a request is seen in time
+            let msg = match self.stream.next().now_or_never() {
+                Some(msg) => msg,
+                None => {
+                    if let Some(request) = queued.pop_front() {
+                        self.answer(request, choking, storage, uploaded).await?;
+                        continue;
+                    }
+                    tokio::select! {
+                        _ = cancel.cancelled() => return Ok(()),
+                        msg = self.stream.next() => msg,
+                    }
                 }
-            } else {
-                BLOCK_MAX
             };
+            let Some(msg) = msg else {
+                debug!("peer hung up");
+                return Ok(());
+            };
+            let msg = msg.context("peer message was invalid")?;
+            self.received(msg.tag)?;
+            match msg.tag {
+                MessageTag::Interested => {
+                    if choking {
+                        self.stream
+                            .send(Message::unchoke())
+                            .await
+                            .context("send unchoke")?;
+                        choking = false;
+                    }
+                }
+                MessageTag::Request => {
+                    Request::from_bytes(&msg.payload)
+                        .context("request message payload should be 12 bytes")?;
+                    if queued.len() < MAX_QUEUED_REQUESTS {
+                        queued.push_back(msg.payload);
+                    } else {
+                        self.reject(msg.payload).await?;
+                    }
+                }
+                MessageTag::Have => self.have(&msg.payload)?,
+                MessageTag::Extended => self.extended(&msg.payload),
+                MessageTag::Cancel => {
+                    // a cancel carries the same index, begin and length as the request it cancels
+                    if let Some(i) = queued.iter().position(|request| *request == msg.payload) {
+                        queued.remove(i);
+                    }
+                }
+                MessageTag::Choke
+                | MessageTag::Unchoke
+                | MessageTag::NotInterested
+                | MessageTag::Piece => {
+                    // we never download from a peer we're seeding to
+                }
+                MessageTag::Suggest | MessageTag::RejectRequest | MessageTag::AllowedFast => {
+                    self.fast_only(msg.tag)?
+                }
+                MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                    self.set_pieces(msg)?
+                }
+            }
+        }
+    }
 
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
+    /// Sends the peer the block it asked for with `request` (a request message's payload), or
+    /// turns it down if we can't serve it.
+    async fn answer(
+        &mut self,
+        request: Vec<u8>,
+        choking: bool,
+        storage: &impl Storage,
+        uploaded: &AtomicUsize,
+    ) -> anyhow::Result<()> {
+        let parsed = Request::from_bytes(&request).expect("queued requests were checked");
+        let block = if choking || parsed.length() as usize > MAX_REQUEST_LENGTH {
+            None
+        } else {
+            let (index, begin) = (parsed.index() as usize, parsed.begin() as usize);
+            storage
+                .read_block(index, begin, parsed.length() as usize)
+                .map_err(|e| debug!("not serving request: {e:#}"))
+                .ok()
+        };
+        let Some(block) = block else {
+            return self.reject(request).await;
+        };
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend(parsed.index().to_be_bytes());
+        payload.extend(parsed.begin().to_be_bytes());
+        payload.extend(&block);
+        self.stream
+            .send(Message {
+                tag: MessageTag::Piece,
+                payload,
+            })
+            .await
+            .context("send piece")?;
+        uploaded.fetch_add(block.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Turns down the peer's `request` (a request message's payload).
+    async fn reject(&mut self, request: Vec<u8>) -> anyhow::Result<()> {
+        // peers without the fast extension just never hear back
+        if self.fast {
             self.stream
                 .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
+                    tag: MessageTag::RejectRequest,
+                    payload: request,
                 })
                 .await
-                .with_context(|| format!("send request for block {block}"))?;
+                .context("send reject request")?;
+        }
+        Ok(())
+    }
 
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
+    /// Tells the peer whether we're interested in its pieces, if that's changed.
+    async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if interested == self.interested {
+            return Ok(());
+        }
+        let msg = if interested {
+            Message::interested()
+        } else {
+            Message::not_interested()
+        };
+        let tag = msg.tag;
+        self.stream
+            .send(msg)
+            .await
+            .with_context(|| format!("send {tag:?} message"))?;
+        debug!(interested, "told peer about our interest");
+        self.interested = interested;
+        Ok(())
+    }
+
+    /// Handles a message that arrives while we're not downloading a piece.
+    fn idle_message(&mut self, msg: Message) -> anyhow::Result<()> {
+        self.received(msg.tag)?;
+        match msg.tag {
+            MessageTag::Unchoke => {
+                if !msg.payload.is_empty() {
+                    warn!("peer sent unchoke with a payload; ignoring the payload");
+                }
+                debug!("unchoked");
+                self.choked = false;
+            }
+            MessageTag::Choke => {
+                debug!("choked");
+                self.choked = true;
+            }
+            MessageTag::Have => {
+                self.have(&msg.payload)?;
+            }
+            MessageTag::Extended => self.extended(&msg.payload),
+            MessageTag::Interested
+            | MessageTag::NotInterested
+            | MessageTag::Request
+            | MessageTag::Cancel => {
+                // not allowing requests for now
+            }
+            MessageTag::Piece => {
+                // piece that we no longer need/are responsible for
+            }
+            MessageTag::Suggest | MessageTag::AllowedFast => {
+                // we only ever download pieces we pick ourselves, once unchoked
+                self.fast_only(msg.tag)?;
+            }
+            MessageTag::RejectRequest => {
+                // the requests it dropped when it choked us; we already handed the piece back
+                self.fast_only(msg.tag)?;
+            }
+            MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                self.set_pieces(msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests the blocks of `piece`, `block_size` bytes at a time (keeping up to
+    /// `pipeline_depth` requests outstanding, or fewer if the peer asked), and assembles them.
+    /// It's an error for the peer to send nothing at all for `block_timeout` meanwhile.
+    ///
+    /// Returns `None` if the peer choked us part-way through, in which case the piece should be
+    /// handed to someone else. The peer discards all our outstanding requests when it chokes us,
+    /// so there's nothing else to return. Also returns `None` if another peer's copy of the piece
+    /// is stored first (in the end game), after cancelling our outstanding requests. The piece is
+    /// _not_ verified.
+    #[instrument(skip_all, fields(piece = piece.index()))]
+    async fn download_piece(
+        &mut self,
+        work: &Scheduler,
+        piece: &piece::Piece,
+        pipeline_depth: usize,
+        block_size: usize,
+        block_timeout: Duration,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let piece_i = piece.index();
+        let piece_size = piece.length();
+        let nblocks = piece_size.div_ceil(block_size);
+        let block_length = |block: usize| crate::block_length(piece_size, block_size, block);
+        // the peer drops requests beyond its queue length, which we have to guess if it didn't say
+        let pipeline_depth = pipeline_depth.min(self.reqq.unwrap_or(DEFAULT_REQQ)).max(1);
+        let mut all_blocks = vec![0u8; piece_size];
+        let mut received = vec![false; nblocks];
+        let mut nreceived = 0;
+        let mut next_block = 0;
+        let mut in_flight = 0;
+        // blocks the peer rejected (with the fast extension), to ask for again
+        let mut retry = VecDeque::new();
+        let mut rejected = vec![false; nblocks];
+
+        while nreceived < nblocks {
+            while in_flight < pipeline_depth && (!retry.is_empty() || next_block < nblocks) {
+                let block = match retry.pop_front() {
+                    Some(block) => block,
+                    None => {
+                        next_block += 1;
+                        next_block - 1
+                    }
+                };
+                let request = Message::request(
+                    piece_i as u32,
+                    (block * block_size) as u32,
+                    block_length(block) as u32,
+                );
+                self.stream
+                    .send(request)
                     .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-
-                match msg.tag {
-                    MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
+                    .with_context(|| format!("send request for block {block}"))?;
+                trace!(block, "requested block");
+                in_flight += 1;
+            }
+
+            // a peer that goes quiet on us would otherwise hold on to the piece forever; giving up
+            // on it hands the piece back to the queue for another peer
+            let msg = tokio::select! {
+                msg = tokio::time::timeout(block_timeout, self.stream.next()) => msg,
+                _ = work.finished(piece_i) => {
+                    debug!("another peer's copy of the piece was stored first");
+                    for block in (0..next_block).filter(|&b| !received[b] && !retry.contains(&b)) {
+                        let cancel = Message::cancel(
+                            piece_i as u32,
+                            (block * block_size) as u32,
+                            block_length(block) as u32,
+                        );
+                        self.stream
+                            .send(cancel)
+                            .await
+                            .with_context(|| format!("send cancel for block {block}"))?;
                     }
-                    MessageTag::Piece => {
-                        let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
-
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
-                        }
+                    return Ok(None);
+                }
+            };
+            let msg = msg
+                .context("peer stopped sending blocks")?
+                .ok_or(Error::PeerDisconnected { peer: self.addr })
+                .context("wait for block")?
+                .context("peer message was invalid")?;
+            self.received(msg.tag)?;
+
+            match msg.tag {
+                MessageTag::Choke => {
+                    if !msg.payload.is_empty() {
+                        warn!("peer sent choke with a payload; ignoring the payload");
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                    debug!("choked");
+                    self.choked = true;
+                    return Ok(None);
+                }
+                MessageTag::Piece => {
+                    let Some(piece) = Piece::ref_from_bytes(&msg.payload[..]) else {
+                        warn!(
+                            len = msg.payload.len(),
+                            "peer sent a truncated piece message"
+                        );
+                        continue;
+                    };
+
+                    // we never ask for more than block_size bytes, so a bigger block is a protocol
+                    // violation whether or not it answers one of our requests
+                    if piece.block().len() > block_size {
+                        warn!(
+                            len = piece.block().len(),
+                            block_size, "peer sent a block bigger than we ever ask for"
+                        );
+                        anyhow::bail!(
+                            "peer sent a {} byte block, but we ask for at most {block_size}",
+                            piece.block().len()
+                        );
                     }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+
+                    // match the reply up with one of our outstanding requests by (index, begin)
+                    let begin = piece.begin() as usize;
+                    let block = begin / block_size;
+                    if piece.index() as usize != piece_i
+                        || !begin.is_multiple_of(block_size)
+                        || block >= next_block
+                        || received[block]
+                    {
+                        // piece that we no longer need/are responsible for
+                    } else if piece.block().len() != block_length(block) {
+                        warn!(
+                            block,
+                            len = piece.block().len(),
+                            expected = block_length(block),
+                            "peer sent a block of the wrong size"
+                        );
+                        anyhow::bail!("peer sent a block of the wrong size");
+                    } else {
+                        trace!(block, "received block");
+                        self.received.record(Instant::now(), piece.block().len());
+                        all_blocks[begin..][..piece.block().len()].copy_from_slice(piece.block());
+                        received[block] = true;
+                        nreceived += 1;
+                        if let Some(i) = retry.iter().position(|&b| b == block) {
+                            // it changed its mind after rejecting the request
+                            retry.remove(i);
+                        } else {
+                            in_flight -= 1;
+                        }
                     }
-                    MessageTag::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                MessageTag::RejectRequest => {
+                    self.fast_only(msg.tag)?;
+                    let Some(reject) = Request::from_bytes(&msg.payload) else {
+                        warn!("peer sent a malformed reject message");
+                        continue;
+                    };
+                    let begin = reject.begin() as usize;
+                    let block = begin / block_size;
+                    if reject.index() as usize != piece_i
+                        || !begin.is_multiple_of(block_size)
+                        || block >= next_block
+                        || received[block]
+                        || retry.contains(&block)
+                    {
+                        // not a request we have outstanding
+                        continue;
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                    in_flight -= 1;
+                    if rejected[block] {
+                        // it won't give us this one; let another peer have a go at the piece
+                        debug!(block, "peer rejected block twice");
+                        return Ok(None);
                     }
+                    trace!(block, "peer rejected block, asking again");
+                    rejected[block] = true;
+                    retry.push_back(block);
+                }
+                MessageTag::Suggest | MessageTag::AllowedFast => {
+                    self.fast_only(msg.tag)?;
+                }
+                MessageTag::Have => {
+                    self.have(&msg.payload)?;
+                }
+                MessageTag::Extended => self.extended(&msg.payload),
+                MessageTag::Interested
+                | MessageTag::NotInterested
+                | MessageTag::Request
+                | MessageTag::Cancel => {
+                    // not allowing requests for now
+                }
+                MessageTag::Unchoke => {
+                    // harmless, we know we're unchoked
+                    debug!("peer sent unchoke while unchoked");
+                }
+                MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                    self.set_pieces(msg)?;
                 }
             }
+        }
+
+        Ok(Some(all_blocks))
+    }
+}
+
+/// How far back [`Rate`] looks when working out a transfer rate.
+const RATE_WINDOW: Duration = Duration::from_secs(20);
 
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
+/// A transfer rate over a sliding window of the last [`RATE_WINDOW`].
+#[derive(Debug, Default)]
+struct Rate {
+    /// When we first recorded a transfer, so that a young rate isn't averaged over a whole window.
+    started: Option<Instant>,
+    /// Every transfer in the window, oldest first.
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Rate {
+    fn record(&mut self, at: Instant, bytes: usize) {
+        self.started.get_or_insert(at);
+        self.samples.push_back((at, bytes));
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if at.saturating_duration_since(oldest) <= RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
         }
+    }
 
-        Ok(())
+    fn per_second(&self, now: Instant) -> f64 {
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        let span = now.saturating_duration_since(started).min(RATE_WINDOW);
+        if span.is_zero() {
+            return 0.0;
+        }
+        let bytes: usize = self
+            .samples
+            .iter()
+            .filter(|&&(at, _)| now.saturating_duration_since(at) <= RATE_WINDOW)
+            .map(|&(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / span.as_secs_f64()
+    }
+}
+
+/// Parses a peer's address as given by a user: `1.2.3.4:6881`, `[::1]:6881` or
+/// `example.com:6881`, looking host names up in DNS (the first address found is used).
+pub fn parse_peer(s: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        anyhow::ensure!(addr.port() != 0, "invalid port 0 in peer address {s:?}");
+        return Ok(addr);
+    }
+    let (host, port) = s
+        .rsplit_once(':')
+        .with_context(|| format!("expected ip:port or host:port, got {s:?}"))?;
+    let port: u16 = port
+        .parse()
+        .ok()
+        .filter(|&port| port != 0)
+        .with_context(|| format!("invalid port {port:?} in peer address {s:?}"))?;
+    if let Some(ip) = host.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        let ip: Ipv6Addr = ip
+            .parse()
+            .with_context(|| format!("invalid IPv6 address {ip:?} in peer address {s:?}"))?;
+        return Ok(SocketAddr::new(ip.into(), port));
+    }
+    anyhow::ensure!(!host.is_empty(), "missing host in peer address {s:?}");
+    anyhow::ensure!(
+        !host.contains(':'),
+        "IPv6 addresses need brackets around them, as in [::1]:6881, not {s:?}"
+    );
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
     }
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve host {host:?}"))?
+        .next()
+        .with_context(|| format!("host {host:?} has no addresses"))
+}
+
+/// The ports conventionally used for BitTorrent, which we fall back to if ours is taken.
+const FALLBACK_PORTS: std::ops::RangeInclusive<u16> = 6881..=6889;
+
+/// Opens the socket other peers can connect to us on.
+///
+/// If `port` is taken, the first free port in 6881-6889 is used instead; check
+/// [`TcpListener::local_addr`] for the port that was actually bound.
+pub(crate) async fn listen(port: u16) -> anyhow::Result<TcpListener> {
+    let mut last_error = None;
+    for port in std::iter::once(port).chain(FALLBACK_PORTS) {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.expect("tried at least one port")).context("bind listening socket")
+}
+
+/// The reserved byte and bit in the handshake that say a peer speaks the extension protocol.
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+/// The reserved byte and bit in the handshake that say a peer speaks the fast extension.
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+/// The reserved bytes we send in every handshake: we speak both extensions.
+const OUR_RESERVED: [u8; 8] = {
+    let mut reserved = [0; 8];
+    reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+    reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+    reserved
+};
+
+/// The extended message id of the extended handshake.
+const EXTENDED_HANDSHAKE: u8 = 0;
+
+/// How long we wait for a bitfield after the handshake before assuming the peer has no pieces.
+const BITFIELD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a peer may go without sending us anything while we have block requests outstanding,
+/// unless [`download::Options::block_timeout`](crate::download::Options::block_timeout) says
+/// otherwise.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest block we serve in one go: the most that fits in a piece message. Peers ask for
+/// [`BLOCK_MAX`](crate::BLOCK_MAX) bytes at a time anyway.
+const MAX_REQUEST_LENGTH: usize = MAX_BLOCK_SIZE;
+
+/// How many of a peer's requests we hold on to while we answer the ones before them.
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// How many of our requests we assume a peer queues up if it doesn't send a `reqq`.
+const DEFAULT_REQQ: usize = 250;
+
+/// How long we wait for our last messages to go out when hanging up on a peer.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a peer may go without sending us _any_ bytes of its handshake.
+///
+/// This is an idle timeout rather than a deadline for the whole handshake, so that a slow peer
+/// that trickles the handshake in is not mistaken for a dead one.
+pub const HANDSHAKE_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn connect_tcp(peer_addr: SocketAddr) -> anyhow::Result<TcpStream> {
+    TcpStream::connect(peer_addr)
+        .await
+        .context("connect to peer")
+}
+
+/// Takes the handshake of a connection a peer opened to us for the torrent with `info_hash` and
+/// answers it, returning the peer's address, the connection and the peer's reserved bytes.
+async fn accept_handshake(
+    peer: TcpStream,
+    info_hash: [u8; 20],
+    encryption: Encryption,
+) -> anyhow::Result<(SocketAddr, mse::Stream, [u8; 8])> {
+    let peer_addr = peer.peer_addr().context("get peer address")?;
+    let mut peer = mse::accept(peer, info_hash, encryption).await?;
+    let mut handshake = Handshake::new([0; 20], [0; 20]);
+    read_handshake(&mut peer, handshake.as_bytes_mut(), HANDSHAKE_IDLE_TIMEOUT).await?;
+    anyhow::ensure!(handshake.length == 19);
+    anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
+    anyhow::ensure!(
+        handshake.info_hash() == info_hash,
+        "peer asked for a torrent we are not downloading"
+    );
+    let reserved = handshake.reserved;
+
+    let mut handshake = Handshake::new(info_hash, *crate::PEER_ID);
+    handshake.reserved = OUR_RESERVED;
+    peer.write_all(handshake.as_bytes_mut())
+        .await
+        .context("write handshake")?;
+    peer.flush().await.context("write handshake")?;
+    debug!("accepted handshake");
+    Ok((peer_addr, peer, reserved))
+}
+
+/// Like `read_exact`, except that it gives up if no bytes arrive for `idle` at any point.
+pub async fn read_handshake<R>(peer: &mut R, buf: &mut [u8], idle: Duration) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = tokio::time::timeout(idle, peer.read(&mut buf[filled..]))
+            .await
+            .with_context(|| format!("peer went idle after {filled} bytes of handshake"))?
+            .context("read handshake")?;
+        anyhow::ensure!(n != 0, "peer closed the connection during the handshake");
+        filled += n;
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -206,6 +1262,18 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// Fast extension: the peer recommends a piece to download.
+    Suggest = 13,
+    /// Fast extension: the peer has every piece, sent instead of a bitfield.
+    HaveAll = 14,
+    /// Fast extension: the peer has no pieces, sent instead of a bitfield.
+    HaveNone = 15,
+    /// Fast extension: the peer won't answer a request (it has the same payload as the request).
+    RejectRequest = 16,
+    /// Fast extension: a piece the peer lets us download even while we're choked.
+    AllowedFast = 17,
+    /// An extension protocol (BEP 10) message; the first byte of the payload says which one.
+    Extended = 20,
 }
 
 #[repr(C)]
@@ -247,11 +1315,13 @@ impl Piece {
         Some(unsafe { &*piece })
     }
 }
+// Debug and Clone copy the fields out rather than referencing them, which packed fields require
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct Handshake {
     pub length: u8,
     pub bittorrent: [u8; 19],
-    pub resverd: [u8; 8],
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -261,12 +1331,32 @@ impl Handshake {
         Handshake {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            resverd: [0; 8],
+            reserved: [0; 8],
             info_hash,
             peer_id,
         }
     }
 
+    /// Parses a received handshake, if it is one: 68 bytes, starting with the protocol string.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Handshake> {
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        let buf = handshake.as_bytes_mut();
+        if bytes.len() != buf.len() {
+            return None;
+        }
+        buf.copy_from_slice(bytes);
+        (handshake.length == 19 && &handshake.bittorrent == b"BitTorrent protocol")
+            .then_some(handshake)
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Self is a POD with repr(c) and repr(packed)
@@ -281,29 +1371,92 @@ pub struct Message {
     pub payload: Vec<u8>,
 }
 
+impl Message {
+    fn without_payload(tag: MessageTag) -> Self {
+        Self {
+            tag,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Tells the peer we want to download from it.
+    pub fn interested() -> Self {
+        Self::without_payload(MessageTag::Interested)
+    }
+
+    /// Tells the peer we don't want anything from it (for now).
+    pub fn not_interested() -> Self {
+        Self::without_payload(MessageTag::NotInterested)
+    }
+
+    /// Lets the peer request blocks from us.
+    pub fn unchoke() -> Self {
+        Self::without_payload(MessageTag::Unchoke)
+    }
+
+    /// Asks for the `length` bytes starting `begin` bytes into piece `index`.
+    pub fn request(index: u32, begin: u32, length: u32) -> Self {
+        let mut request = Request::new(index, begin, length);
+        Self {
+            tag: MessageTag::Request,
+            payload: request.as_bytes_mut().to_vec(),
+        }
+    }
+
+    /// Takes back an earlier [`request`](Self::request) with the same arguments.
+    pub fn cancel(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            tag: MessageTag::Cancel,
+            ..Self::request(index, begin, length)
+        }
+    }
+
+    /// Tells the peer we now have piece `index`.
+    pub fn have(index: u32) -> Self {
+        Self {
+            tag: MessageTag::Have,
+            payload: index.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Tells the peer which pieces we have.
+    pub fn bitfield(bitfield: Bitfield) -> Self {
+        Self {
+            tag: MessageTag::Bitfield,
+            payload: bitfield.payload,
+        }
+    }
+}
+
 pub struct MessageFramer;
 
+/// The longest message (tag and payload) we send or accept.
 const MAX: usize = 2 << 16;
 
+/// The biggest block that fits in a piece message, after its tag, index and begin.
+pub const MAX_BLOCK_SIZE: usize = MAX - 9;
+
 impl Decoder for MessageFramer {
     type Item = Message;
 
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            return Ok(None);
-        }
-
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+        // skip over any keep-alives (zero-length messages) in a loop rather than by recursing, so
+        // that a peer sending a long run of them can't overflow the stack
+        let length = loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
 
-        if length == 0 {
-            // cut 4 bites
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
+            if length != 0 {
+                break length;
+            }
             src.advance(4);
-            return self.decode(src);
-        }
+        };
 
         if src.len() < 5 {
             return Ok(None);
@@ -320,8 +1473,9 @@ impl Decoder for MessageFramer {
             // The full string has not yet arrived.
             //
             // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            // necessary, but is a good idea performance-wise. (saturating, so that the reserve
+            // can never underflow even if the checks above change.)
+            src.reserve((4 + length).saturating_sub(src.len()));
 
             return Ok(None);
         }
@@ -336,6 +1490,12 @@ impl Decoder for MessageFramer {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            13 => MessageTag::Suggest,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -386,7 +1546,7 @@ pub struct Bitfield {
 }
 
 impl Bitfield {
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+    pub fn has_piece(&self, piece_i: usize) -> bool {
         let byte_i = piece_i / (u8::BITS as usize);
         let bit_i = (piece_i % (u8::BITS as usize)) as u32;
         let Some(&byte) = self.payload.get(byte_i) else {
@@ -396,20 +1556,91 @@ impl Bitfield {
         byte & 1u8.rotate_right(bit_i + 1) != 0
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
-        self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
-            (0..u8::BITS).filter_map(move |bit_i| {
-                let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
-                let mask = 1u8.rotate_right(bit_i + 1);
-                (byte & mask != 0).then_some(piece_i)
+    /// Iterates over the pieces the peer has.
+    ///
+    /// Indices at or beyond `num_pieces` are never yielded: the spare bits at the end of the last
+    /// byte should be zero, but a misbehaving peer may set them anyway.
+    pub(crate) fn pieces(&self, num_pieces: usize) -> impl Iterator<Item = usize> + '_ {
+        self.payload
+            .iter()
+            .enumerate()
+            .flat_map(|(byte_i, byte)| {
+                (0..u8::BITS).filter_map(move |bit_i| {
+                    let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
+                    let mask = 1u8.rotate_right(bit_i + 1);
+                    (byte & mask != 0).then_some(piece_i)
+                })
             })
-        })
+            .take_while(move |&piece_i| piece_i < num_pieces)
+    }
+
+    /// A bitfield for `num_pieces` pieces, none of which are set.
+    pub(crate) fn empty(num_pieces: usize) -> Bitfield {
+        Self::from_payload(vec![0; num_pieces.div_ceil(u8::BITS as usize)])
+    }
+
+    /// Whether all of the first `num_pieces` pieces are set.
+    pub(crate) fn is_complete(&self, num_pieces: usize) -> bool {
+        (0..num_pieces).all(|piece_i| self.has_piece(piece_i))
+    }
+
+    /// How many pieces are set.
+    ///
+    /// Spare bits past the last piece are never set (see [`validate`](Self::validate)), so they
+    /// don't throw the count off.
+    pub(crate) fn count_set(&self) -> usize {
+        self.payload
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// The number of pieces the bitfield has room for, which is the torrent's number of pieces
+    /// rounded up to a multiple of 8 if the peer sent a well-formed bitfield.
+    pub(crate) fn num_pieces_hint(&self) -> usize {
+        self.payload.len() * (u8::BITS as usize)
+    }
+
+    /// Checks that the bitfield is exactly as long as a bitfield for `num_pieces` pieces should be,
+    /// and that the spare bits at the end are zero as the spec requires.
+    pub(crate) fn validate(&self, num_pieces: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.payload.len() == num_pieces.div_ceil(u8::BITS as usize),
+            "bitfield has room for {} pieces, but there are {num_pieces}",
+            self.num_pieces_hint()
+        );
+        if let Some(spare) = (num_pieces..self.num_pieces_hint()).find(|&i| self.has_piece(i)) {
+            anyhow::bail!("spare bit {spare} is set, but there are only {num_pieces} pieces");
+        }
+        Ok(())
+    }
+
+    /// Sets piece `piece_i`, which must be one of the pieces the bitfield has room for: the
+    /// bitfield never grows, so that it stays the size of the torrent.
+    pub(crate) fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / (u8::BITS as usize);
+        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
+        assert!(
+            byte_i < self.payload.len(),
+            "piece {piece_i} is out of range for a bitfield of {} pieces",
+            self.num_pieces_hint()
+        );
+        self.payload[byte_i] |= 1u8.rotate_right(bit_i + 1);
     }
 
-    fn from_payload(payload: Vec<u8>) -> Bitfield {
+    /// The bitfield a peer sent as the payload of its `bitfield` message.
+    pub fn from_payload(payload: Vec<u8>) -> Bitfield {
         Self { payload }
     }
+
+    /// A bitfield with every one of `num_pieces` pieces set (and no spare bits).
+    fn full(num_pieces: usize) -> Bitfield {
+        let mut bitfield = Self::empty(num_pieces);
+        for piece_i in 0..num_pieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
 }
 #[repr(C)]
 #[repr(packed)]
@@ -440,6 +1671,14 @@ impl Request {
         u32::from_be_bytes(self.length)
     }
 
+    /// Parses the payload of a request (or cancel, or reject) message.
+    pub fn from_bytes(payload: &[u8]) -> Option<Self> {
+        let payload: &[u8; 12] = payload.try_into().ok()?;
+        let [index, begin, length] = [0, 4, 8]
+            .map(|at| u32::from_be_bytes(payload[at..at + 4].try_into().expect("always 4 bytes")));
+        Some(Self::new(index, begin, length))
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Self is a POD with repr(c) and repr(packed)
@@ -447,3 +1686,361 @@ impl Request {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn framer_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..1024)) {
+            let mut buf = bytes::BytesMut::from(&bytes[..]);
+            // a peer's bytes are decoded until we need more of them or find them invalid
+            while let Ok(Some(_)) = MessageFramer.decode(&mut buf) {}
+            // and we never reserve room for more than one whole message
+            prop_assert!(buf.capacity() <= bytes.len().max(4 + MAX));
+        }
+    }
+
+    #[test]
+    fn framer_regressions() {
+        let decode = |bytes: &[u8]| MessageFramer.decode(&mut bytes::BytesMut::from(bytes));
+        // a length far beyond anything we accept
+        assert!(decode(&[0xff, 0xff, 0xff, 0xff, 7]).is_err());
+        assert!(decode(&[0, 2, 0, 1, 7]).is_err());
+        // a tag we don't know
+        assert!(decode(&[0, 0, 0, 1, 99]).is_err());
+        // a length with no tag after it yet
+        assert!(decode(&[0, 0, 0, 1]).unwrap().is_none());
+        // a message that's all length prefix
+        assert!(decode(&[0, 0, 0]).unwrap().is_none());
+        // a tag-only message
+        let msg = decode(&[0, 0, 0, 1, 2]).unwrap().unwrap();
+        assert_eq!(msg.tag, MessageTag::Interested);
+        assert!(msg.payload.is_empty());
+    }
+
+    #[test]
+    fn long_run_of_keep_alives() {
+        let mut buf = bytes::BytesMut::from(&[0; 4 * 10_000][..]);
+        assert!(MessageFramer.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(&[0; 4 * 10_000]);
+        buf.extend_from_slice(&[0, 0, 0, 5, 4, 0, 0, 0, 9]);
+        let msg = MessageFramer.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.tag, MessageTag::Have);
+        assert_eq!(msg.payload, [0, 0, 0, 9]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_messages_wait_for_the_rest() {
+        let mut encoded = bytes::BytesMut::new();
+        MessageFramer
+            .encode(Message::request(1, 2 * 16384, 16384), &mut encoded)
+            .unwrap();
+        // and the start of the next message, which has to stay in the buffer
+        let next = [0, 0, 0, 1];
+        encoded.extend_from_slice(&next);
+        let len = encoded.len() - next.len();
+        for n in 0..=len + next.len() {
+            let mut buf = bytes::BytesMut::from(&encoded[..n]);
+            let decoded = MessageFramer.decode(&mut buf).unwrap();
+            if n < len {
+                assert!(decoded.is_none(), "decoded a message from {n} bytes");
+                assert_eq!(buf.len(), n);
+            } else {
+                let msg = decoded.unwrap();
+                assert_eq!(msg.tag, MessageTag::Request);
+                assert_eq!(msg.payload.len(), 12);
+                assert_eq!(buf[..], next[..n - len]);
+            }
+        }
+    }
+
+    #[test]
+    fn constructed_messages_on_the_wire() {
+        let encode = |msg: Message| {
+            let mut buf = bytes::BytesMut::new();
+            MessageFramer.encode(msg, &mut buf).unwrap();
+            buf.to_vec()
+        };
+        assert_eq!(encode(Message::interested()), [0, 0, 0, 1, 2]);
+        assert_eq!(encode(Message::not_interested()), [0, 0, 0, 1, 3]);
+        assert_eq!(encode(Message::unchoke()), [0, 0, 0, 1, 1]);
+        assert_eq!(encode(Message::have(0x0102)), [0, 0, 0, 5, 4, 0, 0, 1, 2]);
+        assert_eq!(
+            encode(Message::request(3, 0x4000, 0x4000)),
+            [0, 0, 0, 13, 6, 0, 0, 0, 3, 0, 0, 0x40, 0, 0, 0, 0x40, 0]
+        );
+        assert_eq!(
+            encode(Message::bitfield(Bitfield::full(10))),
+            [0, 0, 0, 3, 5, 0xff, 0xc0]
+        );
+    }
+
+    /// A peer for a torrent with `num_pieces` pieces, connected over loopback to the returned
+    /// stream, which plays the remote end.
+    async fn loopback_peer(num_pieces: usize) -> (Peer, Framed<TcpStream, MessageFramer>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ours, theirs) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let ours = mse::Stream::plaintext(ours.unwrap());
+        let peer = Peer::from_stream(addr, ours, OUR_RESERVED, num_pieces);
+        (peer, Framed::new(theirs.unwrap().0, MessageFramer))
+    }
+
+    #[tokio::test]
+    async fn have_out_of_range_is_rejected() {
+        let (mut peer, _remote) = loopback_peer(10).await;
+        peer.have(&9u32.to_be_bytes()).unwrap();
+        assert!(peer.has_piece(9));
+
+        assert!(peer.have(&10u32.to_be_bytes()).is_err());
+        assert!(peer.have(&u32::MAX.to_be_bytes()).is_err());
+        // the bitfield stayed the size of the torrent
+        assert_eq!(peer.bitfield.num_pieces_hint(), 16);
+        assert_eq!(peer.bitfield.pieces(usize::MAX).collect::<Vec<_>>(), [9]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn set_piece_never_grows_the_bitfield() {
+        Bitfield::empty(10).set_piece(16);
+    }
+
+    #[tokio::test]
+    async fn bitfield_is_accepted_until_another_bep3_message() {
+        let (mut peer, _remote) = loopback_peer(10).await;
+        // an extended handshake first, as Transmission sends it
+        let handshake = Message {
+            tag: MessageTag::Extended,
+            payload: b"\0d1:md11:ut_metadatai2eee".to_vec(),
+        };
+        peer.idle_message(handshake).unwrap();
+        peer.idle_message(Message::bitfield(Bitfield::full(10)))
+            .unwrap();
+        assert!(peer.has_piece(9));
+        // but only the one
+        assert!(peer
+            .idle_message(Message::bitfield(Bitfield::full(10)))
+            .is_err());
+
+        let (mut peer, _remote) = loopback_peer(10).await;
+        peer.idle_message(Message::have(3)).unwrap();
+        assert!(peer
+            .idle_message(Message::bitfield(Bitfield::full(10)))
+            .is_err());
+    }
+
+    /// Storage for a torrent whose every byte is 7.
+    struct Sevens;
+
+    impl Storage for Sevens {
+        fn write_piece(&mut self, _index: usize, _data: &[u8]) -> anyhow::Result<()> {
+            unreachable!("nothing is downloaded while seeding")
+        }
+
+        fn read_block(&self, _index: usize, _begin: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+            Ok(vec![7; len])
+        }
+
+        fn verify_piece(&self, _index: usize, _hash: &[u8; 20]) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_not_served() {
+        let (mut peer, mut remote) = loopback_peer(1).await;
+        let uploaded = AtomicUsize::new(0);
+        let cancel = CancellationToken::new();
+        let leecher = async {
+            let msg = remote.next().await.unwrap().unwrap();
+            assert_eq!(msg.tag, MessageTag::Bitfield);
+            remote.send(Message::interested()).await.unwrap();
+            let msg = remote.next().await.unwrap().unwrap();
+            assert_eq!(msg.tag, MessageTag::Unchoke);
+
+            // all in one go, so the cancel arrives before the first request can be answered
+            let first = Message::request(0, 0, 16384);
+            remote.feed(first.clone()).await.unwrap();
+            remote
+                .feed(Message {
+                    tag: MessageTag::Cancel,
+                    payload: first.payload,
+                })
+                .await
+                .unwrap();
+            remote
+                .feed(Message::request(0, 16384, 16384))
+                .await
+                .unwrap();
+            remote.flush().await.unwrap();
+
+            let msg = remote.next().await.unwrap().unwrap();
+            assert_eq!(msg.tag, MessageTag::Piece);
+            let piece = Piece::ref_from_bytes(&msg.payload).unwrap();
+            assert_eq!(piece.begin(), 16384);
+            cancel.cancel();
+        };
+        let (served, ()) = tokio::join!(peer.serve(&Sevens, &uploaded, cancel.clone()), leecher);
+        served.unwrap();
+        assert_eq!(uploaded.load(Ordering::Relaxed), 16384);
+    }
+
+    #[tokio::test]
+    async fn huge_metadata_is_refused() {
+        let (mut peer, mut remote) = loopback_peer(0).await;
+        let advertise = async {
+            let ours = remote.next().await.unwrap().unwrap();
+            assert_eq!(ours.tag, MessageTag::Extended);
+            remote
+                .send(Message {
+                    tag: MessageTag::Extended,
+                    payload: b"\0d1:md11:ut_metadatai3ee13:metadata_sizei1073741824ee".to_vec(),
+                })
+                .await
+                .unwrap();
+        };
+        let (fetched, ()) = tokio::join!(peer.fetch_metadata([0; 20], 4 << 20), advertise);
+        let e = fetched.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "peer says the metadata is 1.00 GiB (1073741824 bytes), more than the 4.00 MiB \
+             (4194304 bytes) we allow"
+        );
+    }
+
+    #[test]
+    fn rate_is_zero_with_nothing_in_the_window() {
+        let start = Instant::now();
+        let mut rate = Rate::default();
+        assert_eq!(rate.per_second(start), 0.0);
+        // nothing has had time to pass yet
+        rate.record(start, 1000);
+        assert_eq!(rate.per_second(start), 0.0);
+        // and everything has long since left the window
+        assert_eq!(rate.per_second(start + 3 * RATE_WINDOW), 0.0);
+    }
+
+    #[test]
+    fn rate_covers_only_the_window() {
+        let start = Instant::now();
+        let secs = |n: u64| start + Duration::from_secs(n);
+        let mut rate = Rate::default();
+        rate.record(start, 1000);
+        // a young rate is averaged over as long as it's been going
+        assert_eq!(rate.per_second(secs(2)), 500.0);
+
+        rate.record(secs(15), 2000);
+        assert_eq!(rate.per_second(secs(20)), 150.0);
+        // the first transfer falls out of the window, which stops growing
+        assert_eq!(rate.per_second(secs(25)), 100.0);
+        rate.record(secs(30), 4000);
+        assert_eq!(rate.samples.len(), 2);
+        assert_eq!(rate.per_second(secs(30)), 300.0);
+    }
+
+    #[test]
+    fn spare_bits_are_not_pieces() {
+        // 10 pieces, with every bit of the second byte set, the 6 spare ones included
+        let bitfield = Bitfield::from_payload(vec![0b1000_0000, 0xff]);
+        assert_eq!(bitfield.pieces(10).collect::<Vec<_>>(), [0, 8, 9]);
+        assert!(bitfield.validate(10).is_err());
+        assert!(bitfield.validate(16).is_ok());
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out_only_when_idle() {
+        let idle = Duration::from_millis(50);
+        // 20 bytes 10ms apart take four times the idle timeout, but are never idle for long
+        let (mut ours, mut theirs) = tokio::io::duplex(64);
+        let trickle = tokio::spawn(async move {
+            for byte in 0..20 {
+                theirs.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            theirs
+        });
+        let mut buf = [0; 20];
+        read_handshake(&mut ours, &mut buf, idle).await.unwrap();
+        assert_eq!(buf[19], 19);
+
+        // and a peer that stops part of the way goes idle
+        let mut theirs = trickle.await.unwrap();
+        theirs.write_all(&[0; 10]).await.unwrap();
+        let e = read_handshake(&mut ours, &mut buf, idle).await.unwrap_err();
+        assert!(format!("{e:#}").contains("after 10 bytes"), "{e:#}");
+    }
+
+    #[test]
+    fn biggest_block_fits_in_a_message() {
+        let piece = |len: usize| {
+            let mut payload = vec![0; 8];
+            payload.extend(vec![7; len]);
+            Message {
+                tag: MessageTag::Piece,
+                payload,
+            }
+        };
+        let mut buf = bytes::BytesMut::new();
+        MessageFramer
+            .encode(piece(MAX_BLOCK_SIZE), &mut buf)
+            .unwrap();
+        let msg = MessageFramer.decode(&mut buf).unwrap().unwrap();
+        let decoded = Piece::ref_from_bytes(&msg.payload).unwrap();
+        assert_eq!(decoded.block().len(), MAX_BLOCK_SIZE);
+
+        assert!(MessageFramer
+            .encode(piece(MAX_BLOCK_SIZE + 1), &mut buf)
+            .is_err());
+    }
+
+    #[test]
+    fn peer_addresses_in_every_form() {
+        assert_eq!(
+            parse_peer("1.2.3.4:6881").unwrap(),
+            "1.2.3.4:6881".parse().unwrap()
+        );
+        assert_eq!(
+            parse_peer("[::1]:6881").unwrap(),
+            "[::1]:6881".parse().unwrap()
+        );
+        assert_eq!(parse_peer("localhost:6881").unwrap().port(), 6881);
+        assert!(parse_peer("localhost:6881").unwrap().ip().is_loopback());
+
+        let error = |s: &str| parse_peer(s).unwrap_err().to_string();
+        assert_eq!(
+            error("1.2.3.4:99999"),
+            r#"invalid port "99999" in peer address "1.2.3.4:99999""#
+        );
+        assert_eq!(
+            error("1.2.3.4:0"),
+            r#"invalid port 0 in peer address "1.2.3.4:0""#
+        );
+        assert_eq!(
+            error("example.com:port"),
+            r#"invalid port "port" in peer address "example.com:port""#
+        );
+        assert_eq!(
+            error("1.2.3.4"),
+            r#"expected ip:port or host:port, got "1.2.3.4""#
+        );
+        assert_eq!(
+            error("::1:6881"),
+            r#"IPv6 addresses need brackets around them, as in [::1]:6881, not "::1:6881""#
+        );
+        assert_eq!(
+            error("[::g]:6881"),
+            r#"invalid IPv6 address "::g" in peer address "[::g]:6881""#
+        );
+        // .invalid is reserved so that it never resolves
+        assert_eq!(
+            error("nowhere.invalid:6881"),
+            r#"could not resolve host "nowhere.invalid""#
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Peer exchange (BEP 11): peers telling each other which other peers they're connected to.
+//!
+//! PEX rides on the extension protocol (BEP 10): both sides set a bit in the handshake, then send
+//! an extended handshake saying which extensions they speak, and which message id they want each
+//! one sent with.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// The extended message id we ask peers to send us `ut_pex` messages with.
+pub(crate) const UT_PEX: u8 = 1;
+
+/// How often we tell each peer about the peers we've connected to (or lost) since.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most peers we put in either list of a single message, as BEP 11 asks.
+const MAX_PEERS: usize = 50;
+
+/// The `added.f` flag for a peer that has every piece.
+const SEED: u8 = 0x02;
+
+/// The payload of an extended handshake (extended message id 0).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    /// Extension name -> the message id to send it with; 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// How many outstanding requests the sender will queue up before dropping more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// How big the torrent's info dictionary is, if the sender can send it to us (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+/// The payload of a `ut_pex` message.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexMessage {
+    /// Compact IPv4 peers the sender has connected to.
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+    /// One byte of flags (encryption, seed, ...) per peer in `added`.
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    added_f: Vec<u8>,
+    /// Compact IPv4 peers the sender has disconnected from.
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+}
+
+/// The part of the download every connection's PEX needs: who we're connected to, and where to
+/// send peers we hear about.
+#[derive(Debug, Clone)]
+pub(crate) struct Swarm {
+    connected: Arc<Mutex<HashSet<SocketAddr>>>,
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+}
+
+impl Swarm {
+    pub(crate) fn new(discovered: mpsc::UnboundedSender<SocketAddr>) -> Self {
+        Self {
+            connected: Default::default(),
+            discovered,
+        }
+    }
+
+    pub(crate) fn joined(&self, peer: SocketAddr) {
+        self.connected
+            .lock()
+            .expect("swarm lock is never poisoned")
+            .insert(peer);
+    }
+
+    pub(crate) fn left(&self, peer: SocketAddr) {
+        self.connected
+            .lock()
+            .expect("swarm lock is never poisoned")
+            .remove(&peer);
+    }
+}
+
+/// PEX with a single peer.
+#[derive(Debug)]
+pub(crate) struct Exchange {
+    swarm: Swarm,
+    /// The peers we've told this peer about (and not told it we dropped since).
+    told: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl Exchange {
+    pub(crate) fn new(swarm: Swarm) -> Self {
+        Self {
+            swarm,
+            told: HashSet::new(),
+            last_sent: None,
+        }
+    }
+
+    /// Passes on the peers in a `ut_pex` message to the swarm.
+    pub(crate) fn receive(&self, payload: &[u8]) -> anyhow::Result<()> {
+        let message: PexMessage = crate::from_bencode(payload).context("parse ut_pex message")?;
+        anyhow::ensure!(
+            message.added.len().is_multiple_of(6) && message.dropped.len().is_multiple_of(6),
+            "ut_pex peer lists must be whole 6-byte addresses, got {} added and {} dropped bytes",
+            message.added.len(),
+            message.dropped.len()
+        );
+        let added: Vec<_> = compact_peers(&message.added).collect();
+        debug!(
+            added = added.len(),
+            seeds = message.added_f.iter().filter(|&&f| f & SEED != 0).count(),
+            dropped = message.dropped.len() / 6,
+            "got peers through PEX"
+        );
+        for peer in added {
+            // if the download is over, nobody wants the peer any more
+            let _ = self.swarm.discovered.send(peer);
+        }
+        Ok(())
+    }
+
+    /// The `ut_pex` payload to send to `to` now, if it's time for one and we have news.
+    pub(crate) fn next_message(&mut self, to: SocketAddr, now: Instant) -> Option<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < INTERVAL)
+        {
+            return None;
+        }
+        self.last_sent = Some(now);
+
+        let connected = self
+            .swarm
+            .connected
+            .lock()
+            .expect("swarm lock is never poisoned")
+            .clone();
+        // there's no compact IPv4 form for IPv6 peers (they'd go in `added6`), so leave them out
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|&&peer| peer != to && peer.is_ipv4() && !self.told.contains(&peer))
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .told
+            .iter()
+            .filter(|peer| !connected.contains(peer))
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.told.extend(&added);
+        for peer in &dropped {
+            self.told.remove(peer);
+        }
+
+        let message = PexMessage {
+            added_f: vec![0; added.len()],
+            added: to_compact(&added),
+            dropped: to_compact(&dropped),
+        };
+        Some(serde_bencode::to_bytes(&message).expect("ut_pex messages always encode"))
+    }
+}
+
+fn compact_peers(bytes: &[u8]) -> impl Iterator<Item = SocketAddr> + '_ {
+    bytes.chunks_exact(6).filter_map(crate::dht::compact_addr)
+}
+
+fn to_compact(peers: &[SocketAddr]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(6 * peers.len());
+    for peer in peers {
+        if let SocketAddr::V4(peer) = peer {
+            compact.extend(peer.ip().octets());
+            compact.extend(peer.port().to_be_bytes());
+        }
+    }
+    compact
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange() -> (Exchange, mpsc::UnboundedReceiver<SocketAddr>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Exchange::new(Swarm::new(tx)), rx)
+    }
+
+    #[test]
+    fn added_peers_are_passed_on() {
+        let (exchange, mut discovered) = exchange();
+        let mut payload = b"d5:added12:".to_vec();
+        payload.extend([10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0x00, 0x50]);
+        payload.extend(b"7:added.f2:");
+        payload.extend([SEED, 0]);
+        payload.extend(b"7:dropped6:");
+        payload.extend([172, 16, 0, 9, 0x1a, 0xe9]);
+        payload.push(b'e');
+
+        exchange.receive(&payload).unwrap();
+        assert_eq!(
+            discovered.try_recv().unwrap(),
+            "10.0.0.1:6881".parse().unwrap()
+        );
+        assert_eq!(
+            discovered.try_recv().unwrap(),
+            "192.168.1.2:80".parse().unwrap()
+        );
+        // dropped peers are only logged
+        assert!(discovered.try_recv().is_err());
+    }
+
+    #[test]
+    fn ragged_peer_lists_are_refused() {
+        let (exchange, mut discovered) = exchange();
+        let mut payload = b"d5:added7:".to_vec();
+        payload.extend([10, 0, 0, 1, 0x1a, 0xe1, 7]);
+        payload.push(b'e');
+        let err = exchange.receive(&payload).unwrap_err();
+        assert!(err.to_string().contains("7 added"), "{err}");
+
+        let mut payload = b"d7:dropped5:".to_vec();
+        payload.extend([10, 0, 0, 1, 0x1a]);
+        payload.push(b'e');
+        assert!(exchange.receive(&payload).is_err());
+
+        assert!(exchange.receive(b"d5:addedi3ee").is_err());
+        assert!(discovered.try_recv().is_err());
+    }
+
+    #[test]
+    fn sent_messages_read_back() {
+        let (tx, mut discovered) = mpsc::unbounded_channel();
+        let swarm = Swarm::new(tx);
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        swarm.joined(peer);
+        let mut sender = Exchange::new(swarm.clone());
+        let payload = sender
+            .next_message("10.0.0.2:6881".parse().unwrap(), Instant::now())
+            .unwrap();
+
+        Exchange::new(swarm).receive(&payload).unwrap();
+        assert_eq!(discovered.try_recv().unwrap(), peer);
+    }
+}
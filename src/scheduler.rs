@@ -0,0 +1,277 @@
+//! Deciding which piece each peer downloads next.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use crate::{peer::Bitfield, piece::Piece};
+
+/// The pieces that still need downloading, shared by all the peers we download from.
+///
+/// The protocol between the download coordinator and each peer is:
+///
+/// 1. the peer [takes](Scheduler::take) a piece it has, which makes it the only peer working on
+///    that piece; of the pieces it could take, it gets the one the fewest peers have;
+/// 2. the peer downloads the piece's blocks itself, [releases](Scheduler::release) it, and sends
+///    exactly one [`Report`](crate::download::Report) for it;
+/// 3. the coordinator verifies and stores downloaded pieces, [finishing](Scheduler::finish) them,
+///    and [puts back](Scheduler::put_back) every corrupt or abandoned piece so another peer (or
+///    the same one) can try again;
+/// 4. once every piece is done, the coordinator [closes](Scheduler::close) the scheduler, and
+///    peers waiting for work stop participating.
+///
+/// Once there's nothing left to take, a peer that has nothing to do may also take a piece that
+/// a single other peer is still downloading (the "end game"), so that one slow peer doesn't hold
+/// up the end of the download. Whichever copy is stored first wins, and the other peer gives up
+/// on the piece once it's [finished](Scheduler::finished).
+///
+/// Peers that are downloading [join](Scheduler::join) the scheduler, so it knows how many of
+/// them have each piece. Web seeds don't join; they [take](Scheduler::take_for_web_seed) pieces
+/// that no peer has, or any piece while there are few peers.
+pub(crate) struct Scheduler {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+struct State {
+    pieces: Vec<Piece>,
+    /// The pieces that have been taken and not released yet, by piece index, with how many peers
+    /// are downloading each.
+    downloading: HashMap<usize, (Piece, usize)>,
+    /// Which pieces have been stored, by piece index.
+    finished: Vec<bool>,
+    /// How many of the peers that have joined have each piece, by piece index.
+    availability: Vec<usize>,
+    /// How many peers have joined.
+    peers: usize,
+    closed: bool,
+}
+
+impl Scheduler {
+    /// Schedules `pieces`, out of a torrent with `num_pieces` pieces.
+    pub(crate) fn new(pieces: Vec<Piece>, num_pieces: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                pieces,
+                downloading: HashMap::new(),
+                finished: vec![false; num_pieces],
+                availability: vec![0; num_pieces],
+                peers: 0,
+                closed: false,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("scheduler lock is never poisoned")
+    }
+
+    /// Starts counting a peer's pieces towards how rare each piece is, until the returned
+    /// [`PeerPieces`] is dropped.
+    pub(crate) fn join(&self) -> PeerPieces<'_> {
+        self.state().peers += 1;
+        self.changed.notify_waiters();
+        PeerPieces {
+            scheduler: self,
+            counted: Bitfield::empty(self.state().availability.len()),
+        }
+    }
+
+    /// Takes the rarest piece out of those for which `has` returns true, or in the end game,
+    /// one that another peer is downloading.
+    ///
+    /// If there is no such piece, this waits until one is put back. Returns `None` once the
+    /// scheduler is closed.
+    pub(crate) async fn take(&self, has: impl Fn(usize) -> bool) -> Option<Piece> {
+        self.wait_until(|state| {
+            state
+                .take_rarest(|piece_i, _| has(piece_i))
+                .or_else(|| state.take_end_game(&has))
+        })
+        .await
+    }
+
+    /// Takes the rarest piece for a web seed, which has every piece: any piece while fewer than
+    /// `min_peers` peers have joined, and otherwise only pieces that none of them have.
+    ///
+    /// Like [`take`](Self::take), this waits for such a piece, and returns `None` once the
+    /// scheduler is closed.
+    pub(crate) async fn take_for_web_seed(&self, min_peers: usize) -> Option<Piece> {
+        self.wait_until(|state| {
+            let short_of_peers = state.peers < min_peers;
+            state.take_rarest(|_, peers_with_it| short_of_peers || peers_with_it == 0)
+        })
+        .await
+    }
+
+    /// Whether there's a piece to take right now for which `has` returns true.
+    pub(crate) fn wants(&self, has: impl Fn(usize) -> bool) -> bool {
+        let state = self.state();
+        !state.closed && state.can_take(&has)
+    }
+
+    /// Waits until [`wants`](Self::wants) would return true, without taking anything. Returns
+    /// false if the scheduler is closed first.
+    pub(crate) async fn wait_for(&self, has: impl Fn(usize) -> bool) -> bool {
+        self.wait_until(|state| state.can_take(&has).then_some(()))
+            .await
+            .is_some()
+    }
+
+    /// Waits until `f` returns something, calling it again whenever the pieces to take change.
+    /// Returns `None` once the scheduler is closed.
+    async fn wait_until<T>(&self, mut f: impl FnMut(&mut State) -> Option<T>) -> Option<T> {
+        loop {
+            // register interest _before_ looking, so that a put_back in between isn't missed
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state();
+                if state.closed {
+                    return None;
+                }
+                if let Some(found) = f(&mut state) {
+                    return Some(found);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Notes that a peer has stopped downloading `piece_i`, whether or not it got the whole
+    /// piece.
+    pub(crate) fn release(&self, piece_i: usize) {
+        let mut state = self.state();
+        if let Some((_, peers)) = state.downloading.get_mut(&piece_i) {
+            *peers -= 1;
+            if *peers == 0 {
+                state.downloading.remove(&piece_i);
+            }
+        }
+        drop(state);
+        // the piece may be up for the end game now
+        self.changed.notify_waiters();
+    }
+
+    /// Makes a piece available for downloading again.
+    ///
+    /// Nothing happens if another peer is still downloading it (it will put the piece back if it
+    /// fails too), or if the piece is already stored or waiting to be taken.
+    pub(crate) fn put_back(&self, piece: Piece) {
+        let mut state = self.state();
+        let piece_i = piece.index();
+        if state.finished[piece_i]
+            || state.downloading.contains_key(&piece_i)
+            || state
+                .pieces
+                .iter()
+                .any(|waiting| waiting.index() == piece_i)
+        {
+            return;
+        }
+        state.pieces.push(piece);
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Notes that `piece_i` is stored, so any other peer downloading it can give up.
+    pub(crate) fn finish(&self, piece_i: usize) {
+        self.state().finished[piece_i] = true;
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until `piece_i` is [finished](Self::finish), or the scheduler is closed.
+    pub(crate) async fn finished(&self, piece_i: usize) {
+        self.wait_until(|state| state.finished[piece_i].then_some(()))
+            .await;
+    }
+
+    /// Tells every peer waiting for work that there won't be any more.
+    pub(crate) fn close(&self) {
+        self.state().closed = true;
+        self.changed.notify_waiters();
+    }
+}
+
+impl State {
+    /// Takes the rarest piece out of those for which `wanted` returns true, if there are any.
+    ///
+    /// `wanted` is given each piece's index, and how many peers have it.
+    fn take_rarest(&mut self, wanted: impl Fn(usize, usize) -> bool) -> Option<Piece> {
+        let rarest = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| wanted(piece.index(), self.availability[piece.index()]))
+            .min_by_key(|(_, piece)| (self.availability[piece.index()], piece.index()))
+            .map(|(i, _)| i)?;
+        let piece = self.pieces.swap_remove(rarest);
+        self.downloading.insert(piece.index(), (piece.clone(), 1));
+        Some(piece)
+    }
+
+    /// In the end game, takes the rarest piece for which `has` returns true that exactly one
+    /// peer is downloading, if there is one.
+    fn take_end_game(&mut self, has: impl Fn(usize) -> bool) -> Option<Piece> {
+        let piece_i = self.end_game(has)?;
+        let (piece, peers) = self
+            .downloading
+            .get_mut(&piece_i)
+            .expect("end game pieces are being downloaded");
+        *peers += 1;
+        Some(piece.clone())
+    }
+
+    /// The piece [`take_end_game`](Self::take_end_game) would take.
+    fn end_game(&self, has: impl Fn(usize) -> bool) -> Option<usize> {
+        if !self.pieces.is_empty() {
+            return None;
+        }
+        self.downloading
+            .iter()
+            .filter(|&(&piece_i, &(_, peers))| {
+                peers == 1 && !self.finished[piece_i] && has(piece_i)
+            })
+            .map(|(&piece_i, _)| piece_i)
+            .min_by_key(|&piece_i| (self.availability[piece_i], piece_i))
+    }
+
+    /// Whether there's a piece to take for which `has` returns true.
+    fn can_take(&self, has: impl Fn(usize) -> bool) -> bool {
+        self.pieces.iter().any(|piece| has(piece.index())) || self.end_game(has).is_some()
+    }
+}
+
+/// The pieces of one peer that a [`Scheduler`] is counting.
+pub(crate) struct PeerPieces<'s> {
+    scheduler: &'s Scheduler,
+    counted: Bitfield,
+}
+
+impl PeerPieces<'_> {
+    /// Catches up with the pieces the peer has told us about since the last update.
+    pub(crate) fn update(&mut self, bitfield: &Bitfield) {
+        let mut state = self.scheduler.state();
+        let num_pieces = state.availability.len();
+        for piece_i in bitfield.pieces(num_pieces) {
+            if !self.counted.has_piece(piece_i) {
+                self.counted.set_piece(piece_i);
+                state.availability[piece_i] += 1;
+            }
+        }
+    }
+}
+
+impl Drop for PeerPieces<'_> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state();
+        let num_pieces = state.availability.len();
+        for piece_i in self.counted.pieces(num_pieces) {
+            state.availability[piece_i] -= 1;
+        }
+        state.peers -= 1;
+        drop(state);
+        // a web seed may be waiting for the peers to dwindle
+        self.scheduler.changed.notify_waiters();
+    }
+}
@@ -0,0 +1,214 @@
+//! Serving a torrent we already have all of to other peers.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    peer::{self, Encryption, Peer},
+    storage::Storage,
+    torrent::Torrent,
+    tracker::{self, Event, TrackerRequest, TrackerResponse},
+    Error,
+};
+
+/// The most peers we upload to at once; anyone after that is turned away until one leaves.
+const MAX_PEERS: usize = 50;
+
+/// How long we wait for the tracker to take our `stopped` announce on the way out.
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Knobs for [`Torrent::seed`].
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The port to accept peer connections on (falls back to 6881-6889 if taken).
+    pub port: u16,
+    /// Whether to require, allow or refuse encrypted connections.
+    pub encryption: Encryption,
+    /// The proxy to send tracker requests through, rather than whatever the `HTTP_PROXY` and
+    /// `HTTPS_PROXY` environment variables say.
+    pub proxy: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            encryption: Encryption::Disable,
+            proxy: None,
+        }
+    }
+}
+
+pub(crate) async fn all<S>(
+    t: &Torrent,
+    storage: S,
+    options: &Options,
+    cancel: CancellationToken,
+) -> Result<(), Error>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    Ok(seed(t, storage, options, cancel).await?)
+}
+
+async fn seed<S>(
+    t: &Torrent,
+    storage: S,
+    options: &Options,
+    cancel: CancellationToken,
+) -> anyhow::Result<()>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    let storage = Arc::new(storage);
+    let num_pieces = t.info.num_pieces();
+    // never hand out data that doesn't match the torrent; this reads everything, so keep it off
+    // the async runtime
+    let hashes = t.info.pieces.0.clone();
+    let verified = tokio::task::spawn_blocking({
+        let storage = Arc::clone(&storage);
+        move || -> anyhow::Result<()> {
+            for (piece_i, hash) in hashes.iter().enumerate() {
+                let ok = storage
+                    .verify_piece(piece_i, hash)
+                    .with_context(|| format!("read piece {piece_i}"))?;
+                if !ok {
+                    return Err(Error::PieceHashMismatch { piece: piece_i }.into());
+                }
+            }
+            Ok(())
+        }
+    });
+    verified
+        .await
+        .context("verify pieces")?
+        .context("verify data to seed")?;
+    info!(pieces = num_pieces, "all pieces verified, seeding");
+
+    let info_hash = t.info_hash();
+    let listener = peer::listen(options.port).await?;
+    let port = listener
+        .local_addr()
+        .context("get listening address")?
+        .port();
+
+    let uploaded = Arc::new(AtomicUsize::new(0));
+    let client = tracker::client(options.proxy.as_deref())?;
+    let announcer = tokio::spawn(announce(
+        client,
+        t.clone(),
+        port,
+        Arc::clone(&uploaded),
+        cancel.clone(),
+    ));
+
+    let mut peers = JoinSet::new();
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            _ = cancel.cancelled() => break,
+            Some(_) = peers.join_next(), if !peers.is_empty() => continue,
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("failed to accept peer connection: {e:#}");
+                    continue;
+                }
+            },
+        };
+        if peers.len() >= MAX_PEERS {
+            debug!(peer = %peer_addr, "turning peer away, we're uploading to enough already");
+            continue;
+        }
+        let storage = Arc::clone(&storage);
+        let uploaded = Arc::clone(&uploaded);
+        let stop = cancel.child_token();
+        let encryption = options.encryption;
+        peers.spawn(async move {
+            let mut peer =
+                match Peer::accept_to_seed(stream, info_hash, num_pieces, encryption).await {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        warn!(peer = %peer_addr, "failed to accept peer: {e:#}");
+                        return;
+                    }
+                };
+            let reason = match peer.serve(&*storage, &uploaded, stop).await {
+                Ok(()) => "done",
+                Err(e) => {
+                    warn!(peer = %peer_addr, "stopped serving peer: {e:#}");
+                    "misbehaved"
+                }
+            };
+            peer.disconnect(reason).await;
+        });
+    }
+
+    // every peer was told to stop along with us
+    while peers.join_next().await.is_some() {}
+    announcer.await.context("announce to tracker")?;
+    Ok(())
+}
+
+/// Tells the tracker we're seeding, re-announces whenever it asks us to, and says we've stopped
+/// once `cancel` fires.
+async fn announce(
+    client: reqwest::Client,
+    t: Torrent,
+    port: u16,
+    uploaded: Arc<AtomicUsize>,
+    cancel: CancellationToken,
+) {
+    let info_hash = t.info_hash();
+    let mut announce = TrackerRequest {
+        left: 0,
+        ..TrackerRequest::new(&t, port)
+    };
+    let mut after = TrackerResponse::DEFAULT_INTERVAL;
+    loop {
+        announce.uploaded = uploaded.load(Ordering::Relaxed);
+        match TrackerResponse::query(&client, &t, info_hash, &announce).await {
+            Ok(response) => {
+                announce.remember(&response);
+                after = response.reannounce_after();
+            }
+            // try again after another interval; peers that already know us can still find us
+            Err(e) => warn!(
+                "failed to announce to tracker: {:#}",
+                anyhow::Error::from(e)
+            ),
+        }
+        // only the first announce is `started`
+        announce.event = None;
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(after) => {}
+        }
+    }
+
+    let stopped = TrackerRequest {
+        uploaded: uploaded.load(Ordering::Relaxed),
+        event: Some(Event::Stopped),
+        ..announce
+    };
+    // don't hang around for a tracker that's gone away
+    let query = TrackerResponse::query(&client, &t, info_hash, &stopped);
+    match tokio::time::timeout(STOPPED_TIMEOUT, query).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!(
+            "failed to announce stop to tracker: {:#}",
+            anyhow::Error::from(e)
+        ),
+        Err(_) => warn!("tracker took too long to hear that we stopped"),
+    }
+}
@@ -0,0 +1,399 @@
+//! Where downloaded pieces end up.
+//!
+//! The download engine only ever talks to a [`Storage`], so it can run against memory (for tests,
+//! or [`Torrent::download_all`](crate::Torrent::download_all)) just as well as against the files
+//! on disk.
+
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::debug;
+
+use crate::torrent::{Info, Keys};
+
+/// Holds the data of a torrent, addressed by piece.
+pub trait Storage {
+    /// Stores the whole of piece `index`.
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Reads `len` bytes starting `begin` bytes into piece `index`, e.g. to serve a peer's
+    /// request.
+    fn read_block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Whether what's stored for piece `index` has the SHA-1 `hash`.
+    fn verify_piece(&self, index: usize, hash: &[u8; 20]) -> anyhow::Result<bool>;
+
+    /// The pieces an earlier download [recorded](Self::record_completed) as complete, if the
+    /// record is still good. Storage that keeps no record has none.
+    fn completed(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// Records that `pieces` are complete, so that a later download into the same storage can
+    /// take them on trust rather than hash every piece again. By default nothing is recorded.
+    fn record_completed(&mut self, _pieces: &[usize]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// How a torrent's bytes are cut into pieces.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    piece_length: usize,
+    length: usize,
+}
+
+impl Layout {
+    fn new(info: &Info) -> Self {
+        Self {
+            piece_length: info.plength,
+            length: info.length(),
+        }
+    }
+
+    /// The byte range of the whole torrent that `len` bytes at `begin` into piece `index` cover.
+    fn block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Range<usize>> {
+        let piece = self.piece(index)?;
+        let end = begin
+            .checked_add(len)
+            .filter(|&end| end <= piece.len())
+            .with_context(|| format!("block {begin}+{len} is outside piece {index}"))?;
+        Ok(piece.start + begin..piece.start + end)
+    }
+
+    /// The byte range of the whole torrent that piece `index` covers.
+    fn num_pieces(&self) -> usize {
+        self.length.div_ceil(self.piece_length)
+    }
+
+    fn piece(&self, index: usize) -> anyhow::Result<Range<usize>> {
+        let start = index
+            .checked_mul(self.piece_length)
+            .filter(|&start| start < self.length)
+            .with_context(|| format!("there is no piece {index}"))?;
+        Ok(start..self.length.min(start + self.piece_length))
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Keeps the whole torrent in one buffer.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    layout: Layout,
+    bytes: Vec<u8>,
+}
+
+impl MemoryStorage {
+    /// Room for all of `info`'s data, zeroed.
+    pub fn new(info: &Info) -> Self {
+        Self {
+            layout: Layout::new(info),
+            bytes: vec![0; info.length()],
+        }
+    }
+
+    /// The torrent's data, with every file one after the other.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let range = self.layout.piece(index)?;
+        anyhow::ensure!(
+            data.len() == range.len(),
+            "piece {index} is {} bytes, not {}",
+            range.len(),
+            data.len()
+        );
+        self.bytes[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.bytes[self.layout.block(index, begin, len)?].to_vec())
+    }
+
+    fn verify_piece(&self, index: usize, hash: &[u8; 20]) -> anyhow::Result<bool> {
+        Ok(sha1(&self.bytes[self.layout.piece(index)?]) == *hash)
+    }
+}
+
+/// Writes the torrent straight into its files.
+///
+/// Single-file torrents go to the output path itself, multi-file ones to `<output>/<name>/`.
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: Layout,
+    /// Every file with its length, in torrent order.
+    files: Vec<(PathBuf, usize)>,
+    /// Where we record which pieces are complete, if anywhere.
+    resume: Option<ResumeFile>,
+}
+
+/// Where a [`FileStorage`] records its completed pieces, and which torrent they're pieces of.
+#[derive(Debug)]
+struct ResumeFile {
+    path: PathBuf,
+    info_hash: [u8; 20],
+}
+
+/// The contents of a resume file.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeData {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    /// What the files looked like when the record was made; if they've changed since, so may
+    /// have the pieces.
+    files: Vec<FileStamp>,
+    pieces: Vec<usize>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct FileStamp {
+    length: u64,
+    /// Nanoseconds since the Unix epoch.
+    modified: u64,
+}
+
+impl FileStorage {
+    /// Creates (or truncates) every file of the torrent under `output` at its full length, so
+    /// pieces can then be written in any order.
+    ///
+    /// Fails without touching the disk if any path in the torrent would escape `output`.
+    pub fn create(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let files = file_paths(info, output)?;
+        for (path, length) in &files {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+            }
+            let file =
+                fs::File::create(path).with_context(|| format!("create {}", path.display()))?;
+            file.set_len(*length as u64)
+                .with_context(|| format!("allocate {}", path.display()))?;
+        }
+        Ok(Self {
+            layout: Layout::new(info),
+            files,
+            resume: None,
+        })
+    }
+
+    /// Uses the torrent's files that are already under `output` (laid out as by
+    /// [`create`](Self::create)), which must all have the right length.
+    pub fn open(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let files = file_paths(info, output)?;
+        for (path, length) in &files {
+            let metadata =
+                fs::metadata(path).with_context(|| format!("inspect {}", path.display()))?;
+            anyhow::ensure!(
+                metadata.len() == *length as u64,
+                "{} is {} bytes, expected {length}",
+                path.display(),
+                metadata.len()
+            );
+        }
+        Ok(Self {
+            layout: Layout::new(info),
+            files,
+            resume: None,
+        })
+    }
+
+    /// Keeps the record of completed pieces (see [`Storage::completed`]) in the file at `path`,
+    /// for fast resume. The record only counts for the torrent with `info_hash`, and only as long
+    /// as none of the files has changed size or been modified since.
+    pub fn with_resume_file(self, path: PathBuf, info_hash: [u8; 20]) -> Self {
+        Self {
+            resume: Some(ResumeFile { path, info_hash }),
+            ..self
+        }
+    }
+
+    /// The size and modification time of every file, as they are now.
+    fn stamps(&self) -> anyhow::Result<Vec<FileStamp>> {
+        self.files
+            .iter()
+            .map(|(path, _)| {
+                let metadata =
+                    fs::metadata(path).with_context(|| format!("inspect {}", path.display()))?;
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .with_context(|| format!("no modification time for {}", path.display()))?;
+                Ok(FileStamp {
+                    length: metadata.len(),
+                    modified: modified.as_nanos() as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// The pieces in the resume file, if it's for this torrent and these files as they are now.
+    fn read_resume_file(&self, resume: &ResumeFile) -> anyhow::Result<Vec<usize>> {
+        let bytes =
+            fs::read(&resume.path).with_context(|| format!("read {}", resume.path.display()))?;
+        let data: ResumeData = crate::from_bencode(&bytes).context("parse resume file")?;
+        anyhow::ensure!(
+            data.info_hash == resume.info_hash,
+            "resume file is for another torrent"
+        );
+        anyhow::ensure!(
+            data.files == self.stamps()?,
+            "files have changed since the resume file was written"
+        );
+        let num_pieces = self.layout.num_pieces();
+        if let Some(piece_i) = data.pieces.iter().find(|&&i| i >= num_pieces) {
+            anyhow::bail!("resume file has piece {piece_i}, but the torrent has {num_pieces}");
+        }
+        Ok(data.pieces)
+    }
+
+    /// The parts of files that the torrent bytes in `range` are stored in, as (path, offset into
+    /// the file, offset into `range`, length).
+    fn spans(&self, range: Range<usize>) -> impl Iterator<Item = (&Path, u64, usize, usize)> {
+        let mut file_start = 0;
+        self.files.iter().filter_map(move |(path, length)| {
+            let start = range.start.max(file_start);
+            let end = range.end.min(file_start + length);
+            let span = (start < end).then(|| {
+                (
+                    path.as_path(),
+                    (start - file_start) as u64,
+                    start - range.start,
+                    end - start,
+                )
+            });
+            file_start += length;
+            span
+        })
+    }
+
+    fn read(&self, range: Range<usize>) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; range.len()];
+        for (path, file_offset, offset, len) in self.spans(range) {
+            let mut file =
+                fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(file_offset))
+                .and_then(|_| file.read_exact(&mut data[offset..][..len]))
+                .with_context(|| format!("read from {}", path.display()))?;
+        }
+        Ok(data)
+    }
+}
+
+impl Storage for FileStorage {
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let range = self.layout.piece(index)?;
+        anyhow::ensure!(
+            data.len() == range.len(),
+            "piece {index} is {} bytes, not {}",
+            range.len(),
+            data.len()
+        );
+        for (path, file_offset, offset, len) in self.spans(range) {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(file_offset))
+                .and_then(|_| file.write_all(&data[offset..][..len]))
+                .with_context(|| format!("write to {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.read(self.layout.block(index, begin, len)?)
+    }
+
+    fn verify_piece(&self, index: usize, hash: &[u8; 20]) -> anyhow::Result<bool> {
+        Ok(sha1(&self.read(self.layout.piece(index)?)?) == *hash)
+    }
+
+    fn completed(&self) -> Option<Vec<usize>> {
+        let resume = self.resume.as_ref()?;
+        match self.read_resume_file(resume) {
+            Ok(pieces) => Some(pieces),
+            Err(e) => {
+                debug!("not using resume file: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Once every piece is complete there's nothing left to resume, so the resume file is removed
+    /// instead.
+    fn record_completed(&mut self, pieces: &[usize]) -> anyhow::Result<()> {
+        let Some(resume) = &self.resume else {
+            return Ok(());
+        };
+        if pieces.len() == self.layout.num_pieces() {
+            return match fs::remove_file(&resume.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("remove {}", resume.path.display()))
+                }
+                _ => Ok(()),
+            };
+        }
+        let data = ResumeData {
+            info_hash: resume.info_hash.to_vec(),
+            files: self.stamps()?,
+            pieces: pieces.to_vec(),
+        };
+        let bytes = serde_bencode::to_bytes(&data).context("encode resume file")?;
+        fs::write(&resume.path, bytes).with_context(|| format!("write {}", resume.path.display()))
+    }
+}
+
+/// Where each of the torrent's files goes under `output`, with its length.
+///
+/// Single-file torrents go to `output` itself, multi-file ones to `<output>/<name>/`.
+fn file_paths(info: &Info, output: &Path) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+    match &info.keys {
+        Keys::SingleFile { length } => Ok(vec![(output.to_path_buf(), *length)]),
+        Keys::MultiFile { files } => {
+            anyhow::ensure!(
+                is_relative_without_dots(Path::new(&info.name)),
+                "refusing unsafe torrent name {:?}",
+                info.name
+            );
+            let root = output.join(&info.name);
+            let mut paths = Vec::with_capacity(files.len());
+            for file in files {
+                let path: PathBuf = file.path.iter().collect();
+                anyhow::ensure!(
+                    is_relative_without_dots(&path),
+                    "refusing unsafe path {} from the torrent",
+                    path.display()
+                );
+                paths.push((root.join(path), file.length));
+            }
+            Ok(paths)
+        }
+    }
+}
+
+/// Whether `path` names something inside whatever directory it's joined onto: not empty, not
+/// absolute, and with no `..` (or `.`) in it.
+fn is_relative_without_dots(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
@@ -1,21 +1,68 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use sha1::Digest;
+use tokio::io::AsyncReadExt;
+use tokio_util::sync::CancellationToken;
 
-use crate::download::{self, Downloaded};
+use crate::{
+    dht,
+    download::{self, DownloadStats, Downloaded},
+    metadata,
+    peer::Encryption,
+    seed,
+    storage::Storage,
+};
 
 use self::hashes::Hashes;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Torrent {
     // url
     pub announce: String,
     pub info: Info,
+    /// When the torrent was made, in seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The program that made the torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// The character encoding of the strings in `info` (which should be UTF-8 regardless).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// HTTP(S) mirrors of the torrent's files (BEP 19), which we fall back on when there aren't
+    /// enough peers. Torrents with a single web seed may give it as a plain string.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "url_list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub url_list: Option<Vec<String>>,
+    /// The info hash, worked out at most once: when parsing, or on the first
+    /// [`info_hash`](Self::info_hash) call.
+    #[serde(skip)]
+    info_hash: OnceLock<[u8; 20]>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Info {
     pub name: String,
     /// The number of bytes in each piece the file is split into.
@@ -27,29 +74,141 @@ pub struct Info {
     #[serde(rename = "piece length")]
     pub plength: usize,
     pub pieces: Hashes,
+    /// `1` for private torrents (BEP 27), which may only get their peers from the tracker.
+    #[serde(rename = "private", default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
     #[serde(flatten)]
     pub keys: Keys,
+    /// Every other key in `info` (e.g. `source`), which we don't use but have to re-encode as-is
+    /// for the info hash to come out right.
+    #[serde(flatten, deserialize_with = "extra_keys")]
+    pub extra: BTreeMap<String, serde_bencode::value::Value>,
+}
+
+/// Deserializes `url-list`, which is either a list of URLs or a single one.
+fn url_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match UrlList::deserialize(deserializer)? {
+        // an empty string means there are no web seeds
+        UrlList::One(url) if url.is_empty() => None,
+        UrlList::One(url) => Some(vec![url]),
+        UrlList::Many(urls) => Some(urls),
+    })
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Deserializes the keys of `info` that no other field claims.
+///
+/// serde hands every flattened field all the keys the named fields didn't take, so the ones that
+/// belong to [`Keys`] have to be removed again here or they would be encoded twice.
+fn extra_keys<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, serde_bencode::value::Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut extra = BTreeMap::<String, serde_bencode::value::Value>::deserialize(deserializer)?;
+    extra.remove("length");
+    extra.remove("files");
+    Ok(extra)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Keys {
     SingleFile { length: usize },
-    MutilFile { files: Vec<File> },
+    MultiFile { files: Vec<File> },
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct File {
     pub length: usize,
     pub path: Vec<String>,
+    /// Hex MD5 of the file's contents, which some (mostly older) torrents include.
+    ///
+    /// We never check it, but it has to survive re-encoding for the info hash to come out right.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+}
+
+impl Info {
+    /// The total number of bytes across all files.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    /// Checks that the number of piece hashes is what `length` and `plength` call for.
+    ///
+    /// A torrent that gets this wrong is malformed (or malicious), and all the piece size and
+    /// offset math would be off for it.
+    pub fn check_pieces(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.plength > 0, "piece length is 0");
+        let expected = self.length().div_ceil(self.plength);
+        anyhow::ensure!(
+            self.num_pieces() == expected,
+            "torrent has {} piece hashes, but {} bytes in pieces of {} need {expected}",
+            self.num_pieces(),
+            self.length(),
+            self.plength
+        );
+        Ok(())
+    }
+
+    /// The number of bytes in piece `piece_i`.
+    ///
+    /// This is `plength` for every piece except the last, which holds whatever is left over.
+    pub fn piece_size(&self, piece_i: usize) -> usize {
+        if piece_i == self.num_pieces() - 1 {
+            let md = self.length() % self.plength;
+            if md == 0 {
+                self.plength
+            } else {
+                md
+            }
+        } else {
+            self.plength
+        }
+    }
+
+    /// Checks `bytes` against the SHA-1 hash recorded for piece `piece_i`.
+    pub fn verify_piece(&self, piece_i: usize, bytes: &[u8]) -> bool {
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(bytes);
+        let hash: [u8; 20] = hasher.finalize().into();
+        hash == self.pieces.0[piece_i]
+    }
 }
 
 impl Torrent {
+    /// SHA-1 of the bencoded `info` dictionary.
+    ///
+    /// For a torrent read from a file, this hashes the `info` bytes exactly as they appear in the
+    /// file, so keys we don't model and non-canonical encodings can't throw it off. Otherwise
+    /// `info` is re-encoded, which serde_bencode does with sorted keys, i.e. canonically.
+    ///
+    /// The hash is only worked out once, so changing `info` afterwards doesn't change it.
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_bytes = serde_bencode::to_bytes(&self.info).expect("re-encode to serde_bencode");
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&info_bytes);
-        hasher.finalize().into()
+        *self.info_hash.get_or_init(|| {
+            let info_bytes =
+                serde_bencode::to_bytes(&self.info).expect("re-encode to serde_bencode");
+            let mut hasher = sha1::Sha1::new();
+            hasher.update(&info_bytes);
+            hasher.finalize().into()
+        })
     }
 
     pub fn print_tree(&self) {
@@ -57,7 +216,7 @@ impl Torrent {
             Keys::SingleFile { length } => {
                 println!("File length: {}", length);
             }
-            Keys::MutilFile { files } => {
+            Keys::MultiFile { files } => {
                 for file in files {
                     println!("File length: {}", file.length);
                     println!("File path: {:?}", file.path);
@@ -67,23 +226,362 @@ impl Torrent {
     }
 
     pub fn length(&self) -> usize {
-        match &self.info.keys {
-            Keys::SingleFile { length } => *length,
-            Keys::MutilFile { files } => files.iter().map(|file| file.length).sum(),
-        }
+        self.info.length()
+    }
+
+    /// The suggested name of the file, or for a multi-file torrent of the directory the files go
+    /// in.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    /// Whether this is a private torrent, for which any peer discovery other than the tracker
+    /// (DHT, peer exchange, ...) must stay off.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
     }
 
+    /// Reads and parses a `.torrent` file, or standard input if `file` is `-`.
     pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let dot_torrent = tokio::fs::read(file).await.context("read torrent file")?;
-        let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        let file = file.as_ref();
+        let dot_torrent = if file == Path::new("-") {
+            let mut dot_torrent = Vec::new();
+            tokio::io::stdin()
+                .read_to_end(&mut dot_torrent)
+                .await
+                .context("read torrent from stdin")?;
+            dot_torrent
+        } else {
+            tokio::fs::read(file).await.context("read torrent file")?
+        };
+        Self::from_bytes(&dot_torrent)
+    }
+
+    /// Parses the contents of a `.torrent` file, which must have exactly as many piece hashes as
+    /// its length calls for (see [`Info::check_pieces`]).
+    pub fn from_bytes(dot_torrent: &[u8]) -> anyhow::Result<Self> {
+        let mut t: Torrent = crate::from_bencode(dot_torrent).context("parse torrent file")?;
+        t.info.check_pieces().context("invalid torrent file")?;
+        let info = raw_info(dot_torrent).context("find info dictionary in torrent file")?;
+        t.info_hash = OnceLock::from(<[u8; 20]>::from(sha1::Sha1::digest(info)));
         Ok(t)
     }
 
-    pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
-        download::all(self).await
+    /// Builds the torrent with the given info hash by fetching its info dictionary from peers that
+    /// have it (BEP 9), for when the info hash is all we have, as with a magnet link.
+    ///
+    /// The peers are `peers` if there are any, and otherwise whoever the DHT finds. The torrent
+    /// has no tracker (its `announce` is empty), so downloading it finds peers the same way.
+    /// Peers that say the info dictionary is more than `max_metadata_size` bytes (see
+    /// [`DEFAULT_MAX_METADATA_SIZE`]) aren't asked for it.
+    pub async fn from_info_hash(
+        info_hash: [u8; 20],
+        peers: &[SocketAddr],
+        encryption: Encryption,
+        max_metadata_size: usize,
+    ) -> anyhow::Result<Self> {
+        let peers = if peers.is_empty() {
+            let found = dht::get_peers(info_hash)
+                .await
+                .context("look up peers in the DHT")?;
+            anyhow::ensure!(
+                !found.is_empty(),
+                "no peers to fetch the torrent's metadata from: none were given, and the DHT \
+                 knew of none"
+            );
+            found
+        } else {
+            peers.to_vec()
+        };
+        let info = metadata::fetch(info_hash, &peers, encryption, max_metadata_size).await?;
+        let info: Info = crate::from_bencode(&info).context("parse torrent metadata")?;
+        info.check_pieces().context("invalid torrent metadata")?;
+        Ok(Self {
+            announce: String::new(),
+            info,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            url_list: None,
+            // the metadata was checked against this, and re-encoding it may not hash the same if
+            // it wasn't canonical
+            info_hash: OnceLock::from(info_hash),
+        })
+    }
+
+    /// Bencodes the torrent, e.g. to write it out as a `.torrent` file.
+    ///
+    /// Top-level keys we don't model (like `announce-list`) are left out, and the output is
+    /// canonical bencode (sorted keys). So a torrent from [`from_bytes`](Self::from_bytes) doesn't
+    /// always come back byte-for-byte, but it keeps its info hash as long as its `info` was
+    /// canonical to begin with, as practically every torrent's is.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("encode torrent file")
+    }
+
+    /// Builds a torrent for the file or directory at `input`, hashing its contents in pieces of
+    /// `piece_length` bytes, or of [`choose_piece_length`] bytes if that's `None`.
+    ///
+    /// Directory entries are taken in name order, so the same input always gives the same info
+    /// hash.
+    pub fn create(
+        input: &Path,
+        announce: String,
+        piece_length: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        if let Some(piece_length) = piece_length {
+            validate_piece_length(piece_length)?;
+        }
+        let name = input
+            .file_name()
+            .context("input has no file name")?
+            .to_str()
+            .context("input name is not valid UTF-8")?
+            .to_string();
+
+        let is_dir = std::fs::metadata(input).context("inspect input")?.is_dir();
+        let mut paths = Vec::new();
+        if is_dir {
+            list_files(input, &mut Vec::new(), &mut paths)?;
+        } else {
+            paths.push((input.to_path_buf(), Vec::new()));
+        }
+        let piece_length = match piece_length {
+            Some(piece_length) => piece_length,
+            None => {
+                let mut total_len = 0;
+                for (file, _) in &paths {
+                    let metadata = std::fs::metadata(file)
+                        .with_context(|| format!("inspect {}", file.display()))?;
+                    total_len += metadata.len() as usize;
+                }
+                choose_piece_length(total_len)
+            }
+        };
+
+        // pieces span file boundaries, so hash the files as one concatenated stream
+        let mut hashes = Vec::new();
+        let mut piece = Vec::with_capacity(piece_length);
+        let mut files = Vec::with_capacity(paths.len());
+        for (file, path) in paths {
+            let mut reader =
+                std::fs::File::open(&file).with_context(|| format!("open {}", file.display()))?;
+            let mut length = 0;
+            loop {
+                let wanted = piece_length - piece.len();
+                length += (&mut reader)
+                    .take(wanted as u64)
+                    .read_to_end(&mut piece)
+                    .with_context(|| format!("read {}", file.display()))?;
+                if piece.len() < piece_length {
+                    // end of this file
+                    break;
+                }
+                hashes.push(sha1::Sha1::digest(&piece).into());
+                piece.clear();
+            }
+            files.push(File {
+                length,
+                path,
+                md5sum: None,
+            });
+        }
+        if !piece.is_empty() {
+            hashes.push(sha1::Sha1::digest(&piece).into());
+        }
+
+        let keys = if is_dir {
+            Keys::MultiFile { files }
+        } else {
+            Keys::SingleFile {
+                length: files[0].length,
+            }
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("system clock is before 1970")?;
+        Ok(Self {
+            announce,
+            creation_date: i64::try_from(now.as_secs()).ok(),
+            comment: None,
+            created_by: Some(
+                concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).into(),
+            ),
+            encoding: None,
+            url_list: None,
+            info_hash: OnceLock::new(),
+            info: Info {
+                name,
+                plength: piece_length,
+                pieces: Hashes(hashes),
+                private: None,
+                keys,
+                extra: BTreeMap::new(),
+            },
+        })
+    }
+
+    /// Downloads the whole torrent, along with how the download went.
+    ///
+    /// Cancelling `cancel` stops the download (with an error) after telling the tracker we left.
+    pub async fn download_all(
+        &self,
+        options: &download::Options,
+        cancel: CancellationToken,
+    ) -> Result<(Downloaded, DownloadStats), crate::Error> {
+        download::all(self, options, cancel).await
+    }
+
+    /// Downloads the whole torrent into `storage`, rather than into memory.
+    ///
+    /// Cancelling `cancel` stops the download (with an error) after telling the tracker we left.
+    pub async fn download_into(
+        &self,
+        storage: &mut impl Storage,
+        options: &download::Options,
+        cancel: CancellationToken,
+    ) -> Result<DownloadStats, crate::Error> {
+        download::into(self, options, storage, cancel).await
+    }
+
+    /// Uploads the torrent out of `storage`, which must hold all of it, to every peer that asks
+    /// until `cancel` fires.
+    ///
+    /// Every piece is checked against its hash first, so we never hand out bad data.
+    pub async fn seed<S>(
+        &self,
+        storage: S,
+        options: &seed::Options,
+        cancel: CancellationToken,
+    ) -> Result<(), crate::Error>
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        seed::all(self, storage, options, cancel).await
     }
 }
 
+/// Finds the bytes of the `info` value in a bencoded torrent, without decoding anything.
+fn raw_info(dot_torrent: &[u8]) -> Option<&[u8]> {
+    let mut rest = dot_torrent.strip_prefix(b"d")?;
+    while !rest.starts_with(b"e") {
+        let (key, after_key) = rest.split_at(value_len(rest)?);
+        let (value, after_value) = after_key.split_at(value_len(after_key)?);
+        if key == b"4:info" {
+            return Some(value);
+        }
+        rest = after_value;
+    }
+    None
+}
+
+/// The length of the bencoded value at the start of `bytes`.
+///
+/// This walks the value with a loop rather than by recursing, since `bytes` may come from a peer
+/// that nests lists as deep as it likes.
+pub(crate) fn value_len(bytes: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    // how many lists and dicts we're inside
+    let mut depth = 0usize;
+    loop {
+        let rest = &bytes[len..];
+        match rest.first()? {
+            b'i' => len += rest.iter().position(|&b| b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                len += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                len += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = rest.iter().position(|&b| b == b':')?;
+                let n: usize = std::str::from_utf8(&rest[..colon]).ok()?.parse().ok()?;
+                len = len.checked_add(colon + 1)?.checked_add(n)?;
+                if len > bytes.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(len);
+        }
+    }
+}
+
+/// The biggest info dictionary [`Torrent::from_info_hash`] fetches unless told otherwise: room
+/// for over 200,000 piece hashes.
+pub const DEFAULT_MAX_METADATA_SIZE: usize = 4 * 1024 * 1024;
+
+/// The smallest piece length [`Torrent::create`] accepts.
+pub const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// The largest piece length [`Torrent::create`] accepts.
+pub const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// The piece length [`Torrent::create`] uses for `total_len` bytes of content unless told
+/// otherwise.
+///
+/// This is the smallest power of two that keeps the torrent at no more than 2000 pieces (so
+/// usually 1000 or more), within [`MIN_PIECE_LENGTH`] and [`MAX_PIECE_LENGTH`].
+pub fn choose_piece_length(total_len: usize) -> usize {
+    const MAX_PIECES: usize = 2000;
+    total_len
+        .div_ceil(MAX_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Checks that `piece_length` is a power of two between [`MIN_PIECE_LENGTH`] and
+/// [`MAX_PIECE_LENGTH`].
+pub fn validate_piece_length(piece_length: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        piece_length.is_power_of_two(),
+        "piece length {piece_length} is not a power of two"
+    );
+    anyhow::ensure!(
+        (MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length),
+        "piece length {piece_length} is not between {MIN_PIECE_LENGTH} and {MAX_PIECE_LENGTH}"
+    );
+    Ok(())
+}
+
+/// Collects every file under `dir` (recursively, in name order) along with its path relative to
+/// the directory the walk started at.
+fn list_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, Vec<String>)>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("list {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("file name {name:?} is not valid UTF-8"))?;
+        let path = entry.path();
+        prefix.push(name);
+        if std::fs::metadata(&path)
+            .with_context(|| format!("inspect {}", path.display()))?
+            .is_dir()
+        {
+            list_files(&path, prefix, files)?;
+        } else {
+            files.push((path, prefix.clone()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
 mod hashes {
     use serde::{de::Visitor, Deserialize, Serialize};
 
@@ -102,7 +600,7 @@ mod hashes {
         where
             E: serde::de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(serde::de::Error::invalid_length(v.len(), &self));
             }
             let mut data = Vec::new();
@@ -134,3 +632,234 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_len_of_each_kind() {
+        assert_eq!(value_len(b"i42e..."), Some(4));
+        assert_eq!(value_len(b"4:spam..."), Some(6));
+        assert_eq!(value_len(b"l4:spami42ee..."), Some(12));
+        assert_eq!(value_len(b"d3:cow3:mooe..."), Some(12));
+        assert_eq!(value_len(b"5:spam"), None);
+        assert_eq!(value_len(b"l4:spam"), None);
+        assert_eq!(value_len(b"e"), None);
+    }
+
+    #[test]
+    fn value_len_of_deep_nesting() {
+        let nested = [b"l".repeat(1_000_000), b"e".repeat(1_000_000)].concat();
+        assert_eq!(value_len(&nested), Some(nested.len()));
+        assert_eq!(value_len(&b"l".repeat(1_000_000)), None);
+    }
+
+    #[test]
+    fn sample_torrent_has_its_published_info_hash() {
+        let t = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        let published = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+        assert_eq!(hex::encode(t.info_hash()), published);
+        // the second call comes from the cache, and agrees with the first
+        assert_eq!(t.info_hash(), t.info_hash());
+        // re-encoding `info`, as for a torrent we built ourselves, comes out the same
+        let reencoded = serde_bencode::to_bytes(&t.info).unwrap();
+        assert_eq!(hex::encode(sha1::Sha1::digest(reencoded)), published);
+    }
+
+    fn info_of(keys: Keys, plength: usize, num_pieces: usize) -> Info {
+        Info {
+            name: "test".into(),
+            plength,
+            pieces: Hashes(vec![[0; 20]; num_pieces]),
+            private: None,
+            keys,
+            extra: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn last_piece_is_full_when_the_length_is_a_multiple() {
+        let info = info_of(Keys::SingleFile { length: 3 * 16384 }, 16384, 3);
+        assert!(info.check_pieces().is_ok());
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(info.piece_size(2), 16384);
+    }
+
+    #[test]
+    fn last_piece_holds_the_rest() {
+        let info = info_of(Keys::SingleFile { length: 40000 }, 16384, 3);
+        assert!(info.check_pieces().is_ok());
+        assert_eq!(info.piece_size(1), 16384);
+        assert_eq!(info.piece_size(2), 40000 - 2 * 16384);
+
+        // and the same for files that add up to the same length
+        let file = |length| File {
+            length,
+            path: vec!["f".into()],
+            md5sum: None,
+        };
+        let info = info_of(
+            Keys::MultiFile {
+                files: vec![file(30000), file(10000)],
+            },
+            16384,
+            3,
+        );
+        assert_eq!(info.piece_size(2), 40000 - 2 * 16384);
+    }
+
+    /// Checks that a torrent whose info dictionary is `info` (canonical bencode) hashes to
+    /// the SHA-1 of those bytes, and that re-encoding its `info` gives them back exactly.
+    fn assert_info_round_trips(info: &[u8]) -> Torrent {
+        let dot_torrent = [b"d8:announce9:http://t/4:info".as_slice(), info, b"e"].concat();
+        let t = Torrent::from_bytes(&dot_torrent).unwrap();
+        assert_eq!(t.info_hash(), <[u8; 20]>::from(sha1::Sha1::digest(info)));
+        let reencoded = serde_bencode::to_bytes(&t.info).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reencoded),
+            String::from_utf8_lossy(info)
+        );
+        t
+    }
+
+    #[test]
+    fn md5sum_survives_re_encoding() {
+        let t = assert_info_round_trips(
+            b"d5:filesld6:lengthi600e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:aeed\
+              6:lengthi400e4:pathl1:beee4:name3:dir12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        );
+        let Keys::MultiFile { files } = &t.info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(
+            files[0].md5sum.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(files[1].md5sum, None);
+    }
+
+    #[test]
+    fn private_flag_survives_re_encoding() {
+        let t = assert_info_round_trips(
+            b"d6:lengthi1000e4:name5:a.bin12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee",
+        );
+        assert!(t.is_private());
+    }
+
+    #[test]
+    fn unknown_info_keys_survive_re_encoding() {
+        let t = assert_info_round_trips(
+            b"d6:lengthi1000e4:name5:a.bin12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source6:MYSITEe",
+        );
+        // the same info dictionary without `source` would hash to 1390fb6c...
+        assert_eq!(
+            hex::encode(t.info_hash()),
+            "0a37bc08b5a16d0971a4240967da4e60d3ac0f9e"
+        );
+        assert!(t.info.extra.contains_key("source"));
+    }
+
+    #[test]
+    fn info_hash_is_of_the_raw_info_bytes() {
+        // keys out of order, which re-encoding would sort
+        let unsorted: &[u8] = b"d4:name5:a.bin6:lengthi1000e12:piece lengthi16384e\
+                                6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let torrents = [
+            [b"d8:announce9:http://t/4:info".as_slice(), unsorted, b"e"].concat(),
+            // `info` before other keys, one of which mentions 4:info itself
+            [
+                b"d8:announce9:http://t/7:comment10:not 4:info4:info".as_slice(),
+                unsorted,
+                b"8:url-list9:http://w/e",
+            ]
+            .concat(),
+        ];
+        let raw_hash = <[u8; 20]>::from(sha1::Sha1::digest(unsorted));
+        for dot_torrent in torrents {
+            let t = Torrent::from_bytes(&dot_torrent).unwrap();
+            assert_eq!(t.info_hash(), raw_hash);
+            let reencoded = serde_bencode::to_bytes(&t.info).unwrap();
+            assert_ne!(<[u8; 20]>::from(sha1::Sha1::digest(reencoded)), raw_hash);
+        }
+    }
+
+    #[test]
+    fn chosen_piece_lengths() {
+        assert_eq!(choose_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(choose_piece_length(1000), MIN_PIECE_LENGTH);
+        // 2000 pieces of exactly 256 KiB, then one more byte needs the next power of two
+        assert_eq!(choose_piece_length(2000 * 256 * 1024), 256 * 1024);
+        assert_eq!(choose_piece_length(2000 * 256 * 1024 + 1), 512 * 1024);
+        assert_eq!(choose_piece_length(usize::MAX / 4), MAX_PIECE_LENGTH);
+        for len in [1, 1 << 20, 700 << 20, 5 << 30] {
+            assert!(validate_piece_length(choose_piece_length(len)).is_ok());
+        }
+    }
+
+    #[test]
+    fn piece_length_must_be_a_power_of_two_in_range() {
+        assert!(validate_piece_length(256 * 1024).is_ok());
+        let e = validate_piece_length(100_000).unwrap_err();
+        assert_eq!(e.to_string(), "piece length 100000 is not a power of two");
+        assert!(validate_piece_length(MIN_PIECE_LENGTH / 2).is_err());
+        assert!(validate_piece_length(MAX_PIECE_LENGTH * 2).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, [0; 1000]).unwrap();
+        assert!(Torrent::create(&path, "http://t/".into(), Some(100_000)).is_err());
+    }
+
+    #[test]
+    fn creating_the_same_directory_twice_gives_the_same_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("b.bin"), [1; 3000]).unwrap();
+        std::fs::write(dir.path().join("sub/c.bin"), [2; 20000]).unwrap();
+        std::fs::write(dir.path().join("a.bin"), [3; 500]).unwrap();
+
+        let create = || Torrent::create(dir.path(), "http://t/".into(), Some(16384)).unwrap();
+        let first = create();
+        let mut second = create();
+        assert_eq!(first.info_hash(), second.info_hash());
+        // the creation date lives outside `info`, so a later run doesn't change the hash
+        second.creation_date = second.creation_date.map(|date| date + 3600);
+        let reread = Torrent::from_bytes(&second.to_bytes().unwrap()).unwrap();
+        assert_ne!(reread.creation_date, first.creation_date);
+        assert_eq!(reread.info_hash(), first.info_hash());
+    }
+
+    #[test]
+    fn piece_count_must_match_the_length() {
+        let info = info_of(Keys::SingleFile { length: 40000 }, 16384, 2);
+        let e = info.check_pieces().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "torrent has 2 piece hashes, but 40000 bytes in pieces of 16384 need 3"
+        );
+        assert!(info_of(Keys::SingleFile { length: 40000 }, 16384, 4)
+            .check_pieces()
+            .is_err());
+        assert!(info_of(Keys::SingleFile { length: 40000 }, 0, 3)
+            .check_pieces()
+            .is_err());
+
+        let dot_torrent = |pieces: &str| {
+            format!(
+                "d8:announce9:http://t/4:infod6:lengthi40000e4:name4:test\
+                 12:piece lengthi16384e6:pieces{}:{pieces}ee",
+                pieces.len()
+            )
+        };
+        assert!(Torrent::from_bytes(dot_torrent(&"a".repeat(60)).as_bytes()).is_ok());
+        let e = Torrent::from_bytes(dot_torrent(&"a".repeat(40)).as_bytes()).unwrap_err();
+        assert!(
+            format!("{e:#}").starts_with("invalid torrent file: "),
+            "{e:#}"
+        );
+    }
+}
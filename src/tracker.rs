@@ -1,8 +1,16 @@
-use crate::torrent::Torrent;
+use crate::{torrent::Torrent, Error};
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use self::peers::Peers;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use self::peers::{Peers, Peers6};
 
 /// Note: the info hash field is _not_ included.
 #[derive(Debug, Clone, Serialize)]
@@ -29,93 +37,342 @@ pub struct TrackerRequest {
     /// The compact representation is more commonly used in the wild, the non-compact
     /// representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// Our [`TRACKER_KEY`](crate::TRACKER_KEY), which is the same on every announce this session.
+    pub key: String,
+
+    /// Tells the tracker why we're announcing, if it's not just the regular periodic announce.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// The `tracker id` from an earlier response, which the tracker wants back on every announce
+    /// after it.
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+
+    /// How many peers we'd like the tracker to send, if not its default (usually 50).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// We're shutting down gracefully.
+    Stopped,
+    /// The download just finished (but not if it was already complete when we started).
+    Completed,
+}
+
+impl TrackerRequest {
+    /// The request for our first announce, before we've downloaded anything.
+    pub fn new(t: &Torrent, port: u16) -> Self {
+        Self {
+            peer_id: String::from_utf8_lossy(crate::PEER_ID).into_owned(),
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: t.length(),
+            compact: 1,
+            key: crate::TRACKER_KEY.clone(),
+            event: Some(Event::Started),
+            tracker_id: None,
+            numwant: None,
+        }
+    }
+
+    /// Picks up anything from the tracker's `response` that it wants to see on our next
+    /// announces, i.e. its tracker id.
+    pub fn remember(&mut self, response: &TrackerResponse) {
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+    }
+
+    /// The announce query string (without the leading `?`), including the percent-encoded
+    /// `info_hash` that serde_urlencoded can't represent.
+    pub fn to_query_string(&self, info_hash: &[u8; 20]) -> String {
+        let params =
+            serde_urlencoded::to_string(self).expect("tracker requests are always url-encodable");
+        format!("{params}&info_hash={}", urlencode(info_hash))
+    }
+
+    /// The full announce URL for this request to the tracker at `announce`.
+    ///
+    /// The parameters go after any query string the announce URL already has, and any fragment
+    /// is dropped (it would otherwise swallow them).
+    pub fn to_url(&self, announce: &str, info_hash: &[u8; 20]) -> String {
+        let base = announce.split_once('#').map_or(announce, |(base, _)| base);
+        let separator = if !base.contains('?') {
+            "?"
+        } else if base.ends_with(['?', '&']) {
+            ""
+        } else {
+            "&"
+        };
+        format!("{base}{separator}{}", self.to_query_string(info_hash))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
     /// An integer, indicating how often your client should make a request to the tracker in seconds.
     ///
-    /// You can ignore this value for the purposes of this challenge.
+    /// Some trackers leave this out (or send 0); see [`TrackerResponse::reannounce_after`].
+    #[serde(default)]
     pub interval: usize,
 
+    /// The shortest time in seconds the tracker lets us wait between regular announces, if it has
+    /// such a limit. Announcing more often than this can get us banned.
+    #[serde(default, rename = "min interval")]
+    pub min_interval: Option<usize>,
+
+    /// A string we should send back (as `trackerid`) on our later announces.
+    #[serde(default, rename = "tracker id")]
+    pub tracker_id: Option<String>,
+
     /// A string, which contains list of peers that your client can connect to.
     ///
     /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the
-    /// last 2 bytes are the peer's port number.
+    /// last 2 bytes are the peer's port number. Trackers that send the non-compact form may also
+    /// include IPv6 peers here, or give peers by DNS name.
     pub peers: Peers,
+
+    /// Like `peers`, but for IPv6 peers.
+    ///
+    /// Each peer is represented using 18 bytes: a 16 byte IPv6 address followed by a 2 byte port.
+    /// Only sent by trackers that support IPv6.
+    pub peers6: Option<Peers6>,
 }
 
 impl TrackerResponse {
-    pub(crate) async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: t.length(),
-            compact: 1,
+    /// How often to re-announce if the tracker doesn't tell us.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+    /// All the peers the tracker told us about, IPv4 first.
+    pub fn peer_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        let v6 = self.peers6.iter().flat_map(|peers6| &peers6.0);
+        self.peers.addrs.iter().chain(v6).copied()
+    }
+
+    /// How long to wait before announcing to the tracker again.
+    ///
+    /// This is never shorter than the tracker's `min interval`.
+    pub fn reannounce_after(&self) -> Duration {
+        let after = if self.interval == 0 {
+            Self::DEFAULT_INTERVAL
+        } else {
+            Duration::from_secs(self.interval as u64)
         };
+        let min = Duration::from_secs(self.min_interval.unwrap_or(0) as u64);
+        after.max(min)
+    }
 
-        let url_params =
-            serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            t.announce,
-            url_params,
-            &urlencode(&info_hash)
-        );
-        let response = reqwest::get(tracker_url).await.context("query tracker")?;
-        let response = response.bytes().await.context("fetch tracker response")?;
-        let tracker_info: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("parse tracker response")?;
+    /// Announces `request` to the torrent's tracker and parses its response, retrying if the
+    /// tracker can't be reached.
+    pub async fn query(
+        client: &reqwest::Client,
+        t: &Torrent,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<Self, Error> {
+        let tracker_url = request.to_url(&t.announce, &info_hash);
+        // trackers (and their rate limits) don't take kindly to several announces from the same
+        // client at once, so wait for any other announce to this tracker to finish first. the lock
+        // is held through the retries and their backoff too, so a tracker that's struggling isn't
+        // hit by a retry and a fresh announce together
+        let in_flight = announce_lock(&t.announce);
+        let _in_flight = in_flight.lock().await;
+        let response = fetch_with_retry(client, &tracker_url)
+            .await
+            .map_err(Error::TrackerHttp)?;
+        let mut tracker_info: TrackerResponse = crate::from_bencode(&response)?;
+        tracker_info.peers.resolve_hosts().await;
         Ok(tracker_info)
     }
 }
 
+/// What we call ourselves in HTTP requests.
+const USER_AGENT: &str = "your_bittorrent/0.1";
+
+/// How long any HTTP request may take to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long any HTTP request may take altogether, as a backstop: tracker requests have their own,
+/// shorter [`TRACKER_TIMEOUT`].
+const HTTP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Builds the HTTP client for tracker (and web seed) requests, so that every request goes out the
+/// same way and they can share connections.
+///
+/// Requests go through `proxy` if it's given, and otherwise through whatever proxy the standard
+/// `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables name (if any).
+pub fn client(proxy: Option<&str>) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        // some trackers compress their responses
+        .gzip(true);
+    if let Some(proxy) = proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).with_context(|| format!("invalid proxy URL {proxy:?}"))?;
+        builder = builder.proxy(proxy);
+    }
+    builder.build().context("build HTTP client")
+}
+
+/// How many times we try to reach the tracker before giving up.
+const TRACKER_ATTEMPTS: u32 = 3;
+
+/// How long a single tracker request (including reading the response) may take.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// GETs `url`, retrying with exponential backoff (1s, 2s, ...) so that a transient network blip
+/// doesn't abort the whole download. Returns the last error if every attempt fails.
+async fn fetch_with_retry(client: &reqwest::Client, url: &str) -> anyhow::Result<bytes::Bytes> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        let response = tokio::time::timeout(TRACKER_TIMEOUT, async {
+            // the client asks for gzip, and decompresses responses that say they're gzipped
+            let response = client.get(url).send().await.context("query tracker")?;
+            response.bytes().await.context("fetch tracker response")
+        })
+        .await
+        .context("tracker request timed out")
+        .and_then(|response| response);
+        match response {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < TRACKER_ATTEMPTS => {
+                warn!("tracker request failed (attempt {attempt}/{TRACKER_ATTEMPTS}): {e:#}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!("tracker failed {TRACKER_ATTEMPTS} times")));
+            }
+        }
+    }
+}
+
+/// One lock per tracker announce URL, held for the duration of an announce.
+static ANNOUNCES: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+fn announce_lock(announce: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut announces = ANNOUNCES
+        .lock()
+        .expect("announce lock map is never poisoned");
+    Arc::clone(announces.entry(announce.to_string()).or_default())
+}
+
+/// Percent-encodes raw bytes (like an info hash) for a URL query.
+///
+/// Every byte outside the unreserved set (`A-Z a-z 0-9 - . _ ~`) becomes `%xx`, including a
+/// space, which is `%20` rather than `+`.
 pub fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode([byte]));
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push('%');
+            encoded.push_str(&hex::encode([byte]));
+        }
     }
     encoded
 }
 
+impl Peers {
+    /// Looks up the peers the tracker gave by DNS name, adding their addresses to `addrs`.
+    ///
+    /// A name that doesn't resolve is skipped rather than failing the whole announce.
+    pub(crate) async fn resolve_hosts(&mut self) {
+        let lookups = self.hosts.drain(..).map(|(host, port)| async move {
+            // like any other peer, one address will do
+            let addr = tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map(|mut addrs| addrs.next());
+            (host, addr)
+        });
+        for (host, addr) in futures_util::future::join_all(lookups).await {
+            match addr {
+                Ok(Some(addr)) => self.addrs.push(addr),
+                Ok(None) => debug!(host, "skipping tracker peer with no addresses"),
+                Err(e) => debug!(host, "skipping tracker peer that doesn't resolve: {e}"),
+            }
+        }
+    }
+}
+
 mod peers {
     use serde::de::{self, Deserialize, Deserializer, Visitor};
     use serde::ser::{Serialize, Serializer};
     use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers {
+        /// The peers the tracker gave by address.
+        pub addrs: Vec<SocketAddr>,
+        /// The peers the (non-compact) response gave by DNS name instead, as `(host, port)`;
+        /// [`TrackerResponse::query`](super::TrackerResponse::query) resolves these into `addrs`.
+        pub hosts: Vec<(String, u16)>,
+    }
     struct PeersVisitor;
 
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+            formatter.write_str(
+                "a multiple of 6 bytes, each a peer's IPv4 address (4 bytes) and port (2 bytes), \
+                 or a list of peer dictionaries",
+            )
+        }
+
+        // the non-compact form, for trackers that ignore `compact=1`
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut peers = Peers::default();
+            while let Some(peer) = seq.next_element::<PeerDict>()? {
+                match peer.ip.parse::<IpAddr>() {
+                    Ok(ip) => peers.addrs.push(SocketAddr::new(ip, peer.port)),
+                    // not a literal address, so it should be a DNS name, which we look up later
+                    // rather than blocking in the middle of parsing
+                    Err(_) => peers.hosts.push((peer.ip, peer.port)),
+                }
+            }
+            Ok(peers)
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
+            // a truncated (or differently formatted) list would shift every peer after the first
+            if !v.len().is_multiple_of(6) {
+                return Err(E::invalid_length(v.len(), &self));
             }
             // TODO: use array_chunks when stable; then we can also pattern-match in closure args
-            Ok(Peers(
-                v.chunks_exact(6)
+            Ok(Peers {
+                addrs: v
+                    .chunks_exact(6)
                     .map(|slice_6| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
+                        SocketAddr::new(
+                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]).into(),
                             u16::from_be_bytes([slice_6[4], slice_6[5]]),
                         )
                     })
                     .collect(),
-            ))
+                hosts: Vec::new(),
+            })
         }
     }
 
@@ -124,17 +381,101 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
         }
     }
 
+    /// A single entry in the non-compact peer list.
+    #[derive(serde::Deserialize)]
+    struct PeerDict {
+        /// IPv4 or IPv6 address, or a DNS name.
+        ip: String,
+        port: u16,
+        // NOTE: there's also a `peer id`, but we have no use for it
+    }
+
     impl Serialize for Peers {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
         {
-            let mut single_slice = Vec::with_capacity(6 * self.0.len());
+            if !self.hosts.is_empty() {
+                return Err(serde::ser::Error::custom(
+                    "the compact peer list can't hold peers given by name",
+                ));
+            }
+            let mut single_slice = Vec::with_capacity(6 * self.addrs.len());
+            for peer in &self.addrs {
+                let SocketAddr::V4(peer) = peer else {
+                    return Err(serde::ser::Error::custom(
+                        "the compact peer list can only hold IPv4 peers",
+                    ));
+                };
+                single_slice.extend(peer.ip().octets());
+                single_slice.extend(peer.port().to_be_bytes());
+            }
+            serializer.serialize_bytes(&single_slice)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Peers6(pub Vec<SocketAddr>);
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers6;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(
+                "a multiple of 18 bytes, each a peer's IPv6 address (16 bytes) and port (2 bytes)",
+            )
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if !v.len().is_multiple_of(18) {
+                return Err(E::invalid_length(v.len(), &self));
+            }
+            Ok(Peers6(
+                v.chunks_exact(18)
+                    .map(|slice_18| {
+                        let mut ip = [0; 16];
+                        ip.copy_from_slice(&slice_18[..16]);
+                        SocketAddr::V6(SocketAddrV6::new(
+                            Ipv6Addr::from(ip),
+                            u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                            0,
+                            0,
+                        ))
+                    })
+                    .collect(),
+            ))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Peers6Visitor)
+        }
+    }
+
+    impl Serialize for Peers6 {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut single_slice = Vec::with_capacity(18 * self.0.len());
             for peer in &self.0 {
+                let SocketAddr::V6(peer) = peer else {
+                    return Err(serde::ser::Error::custom(
+                        "the compact IPv6 peer list can only hold IPv6 peers",
+                    ));
+                };
                 single_slice.extend(peer.ip().octets());
                 single_slice.extend(peer.port().to_be_bytes());
             }
@@ -142,3 +483,188 @@ mod peers {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_peers() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e")
+                .unwrap();
+        assert_eq!(response.peers.addrs, ["127.0.0.1:6881".parse().unwrap()]);
+        assert!(response.peers.hosts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_compact_peers() {
+        let response = b"d8:intervali60e5:peersl\
+            d2:ip8:10.0.0.17:peer id20:-AA0000-0000000000004:porti6881ee\
+            d2:ip3:::14:porti6882ee\
+            d2:ip9:localhost4:porti6883ee\
+            d2:ip20:no-such-host.invalid4:porti6884ee\
+            ee";
+        let mut response: TrackerResponse = serde_bencode::from_bytes(response).unwrap();
+        assert_eq!(
+            response.peers.addrs,
+            [
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            response.peers.hosts,
+            [
+                ("localhost".to_string(), 6883),
+                ("no-such-host.invalid".to_string(), 6884)
+            ]
+        );
+
+        // the name that doesn't resolve is skipped, and the rest of the peers are kept
+        response.peers.resolve_hosts().await;
+        assert!(response.peers.hosts.is_empty());
+        assert_eq!(response.peers.addrs.len(), 3);
+        let localhost = response.peers.addrs[2];
+        assert!(localhost.ip().is_loopback());
+        assert_eq!(localhost.port(), 6883);
+    }
+
+    #[test]
+    fn compact_peers6() {
+        let response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali60e5:peers0:6:peers6\
+              18:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(
+            response.peer_addrs().collect::<Vec<_>>(),
+            ["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(
+            b"d8:intervali60e5:peers0:6:peers617:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1ae"
+        )
+        .is_err());
+    }
+
+    /// A request with every field known, unlike [`TrackerRequest::new`]'s.
+    fn request() -> TrackerRequest {
+        TrackerRequest {
+            peer_id: "00112233445566778899".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            compact: 1,
+            key: "0123abcd".to_string(),
+            event: Some(Event::Started),
+            tracker_id: None,
+            numwant: None,
+        }
+    }
+
+    #[test]
+    fn query_string() {
+        let info_hash = hex::decode("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        assert_eq!(
+            request().to_query_string(&info_hash.try_into().unwrap()),
+            "peer_id=00112233445566778899&port=6881&uploaded=0&downloaded=0&left=1000&compact=1\
+             &key=0123abcd&event=started\
+             &info_hash=%d6%9f%91%e6%b2%aeLT%24h%d1%07%3aq%d4%ea%13%87%9a%7f"
+        );
+    }
+
+    #[test]
+    fn tracker_id_is_sent_back() {
+        let info_hash = [0; 20];
+        let mut request = request();
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e5:peers0:e").unwrap();
+        request.remember(&response);
+        assert!(!request.to_query_string(&info_hash).contains("trackerid"));
+
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e10:tracker id6:abc 125:peers0:e").unwrap();
+        assert_eq!(response.tracker_id.as_deref(), Some("abc 12"));
+        request.remember(&response);
+        assert!(request
+            .to_query_string(&info_hash)
+            .contains("&trackerid=abc+12&"));
+
+        // a later response without one doesn't make us forget it
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e5:peers0:e").unwrap();
+        request.remember(&response);
+        assert_eq!(request.tracker_id.as_deref(), Some("abc 12"));
+    }
+
+    #[test]
+    fn announce_urls() {
+        let info_hash = [0x61; 20];
+        let query = request().to_query_string(&info_hash);
+        let url = |announce| request().to_url(announce, &info_hash);
+        assert_eq!(url("http://t/a"), format!("http://t/a?{query}"));
+        assert_eq!(
+            url("http://t/a.php?x=1"),
+            format!("http://t/a.php?x=1&{query}")
+        );
+        assert_eq!(url("http://t/a?"), format!("http://t/a?{query}"));
+        assert_eq!(url("http://t/a?x=1&"), format!("http://t/a?x=1&{query}"));
+        assert_eq!(url("http://t/a#frag"), format!("http://t/a?{query}"));
+        assert_eq!(
+            url("http://t/a?x=1#frag?y=2"),
+            format!("http://t/a?x=1&{query}")
+        );
+    }
+
+    #[test]
+    fn urlencode_escapes_all_but_unreserved() {
+        assert_eq!(urlencode(&[0xff; 20]), "%ff".repeat(20));
+        assert_eq!(urlencode(&[b' '; 20]), "%20".repeat(20));
+        let mut unreserved = [b'a'; 20];
+        unreserved[..5].copy_from_slice(b"Z9-._");
+        unreserved[5] = b'~';
+        assert_eq!(urlencode(&unreserved), "Z9-._~aaaaaaaaaaaaaa");
+    }
+
+    #[test]
+    fn reannounce_after_respects_min_interval() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e12:min intervali300e5:peers0:e").unwrap();
+        assert_eq!(response.interval, 60);
+        assert_eq!(response.min_interval, Some(300));
+        assert_eq!(response.reannounce_after(), Duration::from_secs(300));
+
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali600e12:min intervali300e5:peers0:e").unwrap();
+        assert_eq!(response.reannounce_after(), Duration::from_secs(600));
+
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali0e5:peers0:e").unwrap();
+        assert_eq!(response.min_interval, None);
+        assert_eq!(
+            response.reannounce_after(),
+            TrackerResponse::DEFAULT_INTERVAL
+        );
+    }
+
+    #[test]
+    fn numwant_only_when_set() {
+        let info_hash = [0; 20];
+        let mut request = request();
+        assert!(!request.to_query_string(&info_hash).contains("numwant"));
+        request.numwant = Some(200);
+        assert!(request
+            .to_query_string(&info_hash)
+            .contains("&numwant=200&"));
+    }
+
+    #[test]
+    fn peers_must_be_whole_addresses() {
+        let e = serde_bencode::from_bytes::<TrackerResponse>(
+            b"d8:intervali60e5:peers7:\x7f\x00\x00\x01\x1a\xe1\x00e",
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("Invalid Length: 7 "), "{e}");
+    }
+}
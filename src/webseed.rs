@@ -0,0 +1,242 @@
+//! Downloading pieces over HTTP from a torrent's web seeds (BEP 19), for when there aren't enough
+//! peers to get them from.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::{header, StatusCode, Url};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{
+    download::Report,
+    scheduler::Scheduler,
+    torrent::{File, Keys, Torrent},
+};
+
+/// Web seeds only download pieces that some peer has while fewer than this many peers are
+/// downloading; they always download the pieces that no peer has.
+pub(crate) const MIN_PEERS: usize = 3;
+
+/// How long fetching one piece's worth of a file (or all of a file) may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many pieces in a row a web seed may fail to deliver before we stop asking it.
+const MAX_FAILURES: usize = 3;
+
+/// The biggest file we'll download whole from a web seed that doesn't support range requests.
+const MAX_WHOLE_FILE: usize = 32 * 1024 * 1024;
+
+/// One HTTP(S) mirror of the torrent's files.
+pub(crate) struct WebSeed {
+    /// For a single-file torrent, the file itself (or the directory it's in, if this ends with
+    /// `/`); for a multi-file torrent, the directory the torrent's own directory is in.
+    url: Url,
+    client: reqwest::Client,
+    /// The last file we had to download whole because the server ignored our range request, so
+    /// its other pieces don't download it all over again.
+    whole_file: Option<(Url, bytes::Bytes)>,
+}
+
+impl WebSeed {
+    pub(crate) fn new(url: &str, client: reqwest::Client) -> anyhow::Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("invalid web seed URL {url:?}"))?;
+        anyhow::ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "web seed {url} isn't HTTP(S)"
+        );
+        anyhow::ensure!(!url.cannot_be_a_base(), "web seed {url} has no path");
+        Ok(Self {
+            url,
+            client,
+            whole_file: None,
+        })
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Where the web seed serves `file`.
+    fn file_url(&self, t: &Torrent, file: &File) -> Url {
+        let mut url = self.url.clone();
+        if matches!(t.info.keys, Keys::SingleFile { .. }) && !url.path().ends_with('/') {
+            return url;
+        }
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .expect("checked the URL can be a base in new");
+            segments.pop_if_empty();
+            if let Keys::MultiFile { .. } = t.info.keys {
+                segments.push(&t.info.name);
+            }
+            // this percent-encodes each part of the path
+            segments.extend(&file.path);
+        }
+        url
+    }
+
+    /// Downloads piece `piece_i`, from however many files it spans.
+    ///
+    /// The piece isn't checked against its hash.
+    async fn fetch_piece(&mut self, t: &Torrent, piece_i: usize) -> anyhow::Result<Vec<u8>> {
+        let start = piece_i * t.info.plength;
+        let end = start + t.info.piece_size(piece_i);
+        let mut piece = Vec::with_capacity(end - start);
+        let mut file_start = 0;
+        for file in crate::download::files(t) {
+            let file_end = file_start + file.length;
+            let (from, to) = (start.max(file_start), end.min(file_end));
+            if from < to {
+                let url = self.file_url(t, &file);
+                let bytes = self
+                    .fetch_range(url, from - file_start, to - from, file.length)
+                    .await?;
+                piece.extend_from_slice(&bytes);
+            }
+            file_start = file_end;
+        }
+        Ok(piece)
+    }
+
+    /// Downloads `len` bytes starting `offset` bytes into the file (of `file_length` bytes) at
+    /// `url`.
+    async fn fetch_range(
+        &mut self,
+        url: Url,
+        offset: usize,
+        len: usize,
+        file_length: usize,
+    ) -> anyhow::Result<bytes::Bytes> {
+        if let Some((whole_url, whole)) = &self.whole_file {
+            if *whole_url == url {
+                return Ok(whole.slice(offset..offset + len));
+            }
+        }
+        let request = self.client.get(url.clone()).header(
+            header::RANGE,
+            format!("bytes={offset}-{}", offset + len - 1),
+        );
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let response = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("request {url}"))?;
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT => {}
+                StatusCode::OK => {
+                    // the server doesn't do ranges, and is sending the whole file instead
+                    anyhow::ensure!(
+                        file_length <= MAX_WHOLE_FILE,
+                        "{url} doesn't support range requests, and is too big to download whole"
+                    );
+                }
+                status => anyhow::bail!("{url} answered a range request with {status}"),
+            }
+            let whole = response.status() == StatusCode::OK;
+            let body = response
+                .bytes()
+                .await
+                .with_context(|| format!("read {url}"))?;
+            anyhow::Ok((whole, body))
+        })
+        .await
+        .with_context(|| format!("request for {url} timed out"))?;
+        let (whole, body) = response?;
+
+        if whole {
+            anyhow::ensure!(
+                body.len() == file_length,
+                "{url} is {} bytes, expected {file_length}",
+                body.len()
+            );
+            let range = body.slice(offset..offset + len);
+            self.whole_file = Some((url, body));
+            return Ok(range);
+        }
+        anyhow::ensure!(
+            body.len() == len,
+            "{url} sent {} bytes for a range of {len}",
+            body.len()
+        );
+        Ok(body)
+    }
+}
+
+/// Downloads the pieces the scheduler hands `seed`, until there are none left or the web seed
+/// fails [`MAX_FAILURES`] times in a row.
+///
+/// Unlike pieces from peers, each piece is checked against its hash before it's reported, and a
+/// web seed that sends a corrupt piece isn't asked again: it's most likely serving a different
+/// version of the files.
+pub(crate) async fn participate(
+    mut seed: WebSeed,
+    t: &Torrent,
+    work: &Scheduler,
+    reports: mpsc::Sender<Report>,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    loop {
+        let piece = tokio::select! {
+            _ = cancel.cancelled() => break,
+            piece = work.take_for_web_seed(MIN_PEERS) => match piece {
+                Some(piece) => piece,
+                // every piece has been downloaded
+                None => break,
+            },
+        };
+        let piece_i = piece.index();
+        let fetched = tokio::select! {
+            _ = cancel.cancelled() => {
+                work.release(piece_i);
+                let _ = reports.send(Report::Abandoned { piece }).await;
+                break;
+            }
+            fetched = seed.fetch_piece(t, piece_i) => fetched,
+        };
+        work.release(piece_i);
+        let fetched = match fetched {
+            Ok(bytes) => crate::download::verify_blocking(bytes, t.info.pieces.0[piece_i]).await,
+            Err(e) => Err(e),
+        };
+        let report = match fetched {
+            Ok((bytes, true)) => {
+                debug!(
+                    piece = piece_i,
+                    url = seed.url(),
+                    "downloaded piece from web seed"
+                );
+                failures = 0;
+                Report::WebSeeded {
+                    url: seed.url().to_string(),
+                    piece,
+                    bytes,
+                }
+            }
+            Ok((_, false)) => {
+                let _ = reports.send(Report::Abandoned { piece }).await;
+                anyhow::bail!("web seed sent a corrupt copy of piece {piece_i}");
+            }
+            Err(e) => {
+                let _ = reports.send(Report::Abandoned { piece }).await;
+                failures += 1;
+                if failures >= MAX_FAILURES {
+                    return Err(e.context(format!("failed {MAX_FAILURES} pieces in a row")));
+                }
+                warn!(piece = piece_i, url = seed.url(), "web seed failed: {e:#}");
+                // give a struggling server a moment before asking it again
+                tokio::time::sleep(Duration::from_secs(failures as u64)).await;
+                continue;
+            }
+        };
+        if reports.send(report).await.is_err() {
+            // the download is over
+            break;
+        }
+    }
+    Ok(())
+}
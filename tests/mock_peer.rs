@@ -0,0 +1,978 @@
+//! End-to-end downloads against a mock peer on loopback.
+//!
+//! The mock peer speaks the wire protocol by hand rather than through `MessageFramer`, so that it
+//! catches regressions in our framing and handshake as well as in how we assemble blocks.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{download, peer::parse_peer, storage::FileStorage, Storage, Torrent};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+
+const PIECE_LENGTH: usize = 16 * 1024;
+
+/// A torrent for `data`, which is written to a file in `dir`.
+fn torrent_for(dir: &tempfile::TempDir, data: &[u8]) -> Torrent {
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, data).unwrap();
+    Torrent::create(&path, String::new(), Some(PIECE_LENGTH)).unwrap()
+}
+
+/// Content that differs at every offset, so a block in the wrong place is noticed.
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// How a mock peer behaves where peers differ.
+#[derive(Clone, Default)]
+struct Behavior {
+    /// Send an extended handshake before the bitfield, as Transmission does.
+    extended_first: bool,
+    /// Send the handshake a byte at a time, with a pause after each.
+    trickle_handshake: bool,
+    /// Send this `reqq` in an extended handshake.
+    reqq: Option<usize>,
+    /// Hold on to requests until we stop sending them, counting the most we had outstanding at
+    /// once in `max_outstanding`.
+    hold_requests: bool,
+    max_outstanding: Arc<AtomicUsize>,
+    /// Say every block starts a byte later than the one asked for.
+    mislabel_blocks: bool,
+    /// Wait this long before unchoking us.
+    unchoke_after: Duration,
+    /// Never answer a request, as if it went quiet once it had unchoked us.
+    silent: bool,
+    /// Counts the cancel messages we send.
+    cancels: Arc<AtomicUsize>,
+    /// Send blocks of the right length, but with their first byte flipped.
+    corrupt: bool,
+    /// Listen on this loopback address rather than 127.0.0.1, to look like another host.
+    ip: Option<IpAddr>,
+    /// Hang up as soon as we ask for a block, having unchoked us.
+    hang_up_on_request: bool,
+    /// Send twice as many bytes as we ask for, padded with zeros past the end of the data.
+    double_blocks: bool,
+    /// Counts the blocks it sends.
+    sent: Arc<AtomicUsize>,
+}
+
+/// Starts a mock peer that has all of `data`, returning the address it listens on.
+///
+/// It serves one connection: it answers the handshake, says it has every piece, unchokes us once
+/// we're interested, and answers every request out of `data`, until we hang up.
+async fn mock_peer(t: &Torrent, data: Vec<u8>) -> SocketAddr {
+    mock_peer_with(t, data, Behavior::default()).await
+}
+
+/// Like [`mock_peer`], but behaving as `behavior` says.
+async fn mock_peer_with(t: &Torrent, data: Vec<u8>, behavior: Behavior) -> SocketAddr {
+    let ip = behavior.ip.unwrap_or(Ipv4Addr::LOCALHOST.into());
+    let listener = TcpListener::bind((ip, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = t.info_hash();
+    let num_pieces = t.info.num_pieces();
+    let plength = t.info.plength;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        serve(&mut stream, info_hash, num_pieces, plength, &data, behavior).await;
+    });
+    addr
+}
+
+async fn serve(
+    stream: &mut TcpStream,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    plength: usize,
+    data: &[u8],
+    behavior: Behavior,
+) {
+    let mut handshake = [0; 68];
+    stream.read_exact(&mut handshake).await.unwrap();
+    assert_eq!(&handshake[..20], b"\x13BitTorrent protocol");
+    assert_eq!(handshake[28..48], info_hash);
+
+    let mut reply = handshake;
+    // the extension protocol bit, and nothing else
+    reply[20..28].copy_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+    reply[48..].copy_from_slice(b"-MOCK00-000000000000");
+    if behavior.trickle_handshake {
+        for byte in reply {
+            stream.write_all(&[byte]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+    } else {
+        stream.write_all(&reply).await.unwrap();
+    }
+
+    if behavior.extended_first {
+        send(stream, 20, b"\0d1:md6:ut_pexi1ee1:v4:Mocke").await;
+    }
+    if let Some(reqq) = behavior.reqq {
+        send(stream, 20, format!("\0d1:mde4:reqqi{reqq}ee").as_bytes()).await;
+    }
+    let mut bitfield = vec![0u8; num_pieces.div_ceil(8)];
+    for piece_i in 0..num_pieces {
+        bitfield[piece_i / 8] |= 0x80 >> (piece_i % 8);
+    }
+    send(stream, 5, &bitfield).await;
+
+    let mut held: Vec<Vec<u8>> = Vec::new();
+    loop {
+        if !held.is_empty() {
+            // we've gone quiet once nothing more arrives for a while
+            let quiet = Duration::from_millis(50);
+            if tokio::time::timeout(quiet, stream.peek(&mut [0]))
+                .await
+                .is_err()
+            {
+                for request in held.drain(..) {
+                    answer(stream, plength, data, &request, &behavior).await;
+                }
+                continue;
+            }
+        }
+        let mut len = [0; 4];
+        if stream.read_exact(&mut len).await.is_err() {
+            // we hung up
+            return;
+        }
+        let mut msg = vec![0; u32::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut msg).await.is_err() {
+            return;
+        }
+        match msg.first() {
+            // interested
+            Some(2) => {
+                tokio::time::sleep(behavior.unchoke_after).await;
+                send(stream, 1, &[]).await;
+            }
+            // request
+            Some(6) if behavior.hold_requests => {
+                held.push(msg);
+                behavior
+                    .max_outstanding
+                    .fetch_max(held.len(), Ordering::Relaxed);
+            }
+            Some(6) if behavior.hang_up_on_request => return,
+            Some(6) if behavior.silent => {}
+            Some(6) => answer(stream, plength, data, &msg, &behavior).await,
+            // cancel
+            Some(8) => {
+                behavior.cancels.fetch_add(1, Ordering::Relaxed);
+            }
+            // keep-alives, not interested, have, extended messages and the like
+            _ => {}
+        }
+    }
+}
+
+/// Sends the block that request message `msg` asks for, mangled as `behavior` says.
+async fn answer(
+    stream: &mut TcpStream,
+    plength: usize,
+    data: &[u8],
+    msg: &[u8],
+    behavior: &Behavior,
+) {
+    let field = |at: usize| u32::from_be_bytes(msg[1 + at..5 + at].try_into().unwrap()) as usize;
+    let (index, begin, length) = (field(0), field(4), field(8));
+    let start = index * plength + begin;
+    let mut payload = msg[1..5].to_vec();
+    payload.extend((begin as u32 + u32::from(behavior.mislabel_blocks)).to_be_bytes());
+    payload.extend(&data[start..start + length]);
+    if behavior.double_blocks {
+        payload.resize(payload.len() + length, 0);
+    }
+    if behavior.corrupt {
+        payload[8] ^= 0xff;
+    }
+    send(stream, 7, &payload).await;
+    behavior.sent.fetch_add(1, Ordering::Relaxed);
+}
+
+async fn send(stream: &mut TcpStream, tag: u8, payload: &[u8]) {
+    let mut msg = (payload.len() as u32 + 1).to_be_bytes().to_vec();
+    msg.push(tag);
+    msg.extend(payload);
+    stream.write_all(&msg).await.unwrap();
+}
+
+/// Download options that use only `peer`.
+fn options(peer: SocketAddr) -> download::Options {
+    download::Options {
+        // any free port, so tests can run side by side
+        port: 0,
+        peers: vec![peer],
+        ..Default::default()
+    }
+}
+
+/// Storage that keeps track of which pieces were written, in order.
+struct Recording<S> {
+    inner: S,
+    written: Vec<usize>,
+}
+
+impl<S: Storage> Storage for Recording<S> {
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.written.push(index);
+        self.inner.write_piece(index, data)
+    }
+
+    fn read_block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.inner.read_block(index, begin, len)
+    }
+
+    fn verify_piece(&self, index: usize, hash: &[u8; 20]) -> anyhow::Result<bool> {
+        self.inner.verify_piece(index, hash)
+    }
+}
+
+/// Storage that takes its time over every piece it writes, keeping track of the most pieces that
+/// had been sent (one block each) but not written yet.
+struct Slow<S> {
+    inner: S,
+    sent: Arc<AtomicUsize>,
+    written: usize,
+    max_unwritten: usize,
+}
+
+impl<S: Storage> Storage for Slow<S> {
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let unwritten = self.sent.load(Ordering::Relaxed) - self.written;
+        self.max_unwritten = self.max_unwritten.max(unwritten);
+        std::thread::sleep(Duration::from_millis(20));
+        self.written += 1;
+        self.inner.write_piece(index, data)
+    }
+
+    fn read_block(&self, index: usize, begin: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.inner.read_block(index, begin, len)
+    }
+
+    fn verify_piece(&self, index: usize, hash: &[u8; 20]) -> anyhow::Result<bool> {
+        self.inner.verify_piece(index, hash)
+    }
+}
+
+#[tokio::test]
+async fn downloads_two_piece_torrent() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    assert_eq!(t.info.num_pieces(), 2);
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let (downloaded, stats) = t
+        .download_all(&options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    let files: Vec<_> = downloaded.into_iter().collect();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].bytes(), data);
+    assert_eq!(stats.peers_used(), 1);
+}
+
+#[tokio::test]
+async fn downloads_from_an_ipv6_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+
+    let behavior = Behavior {
+        ip: Some(Ipv6Addr::LOCALHOST.into()),
+        ..Default::default()
+    };
+    let addr = mock_peer_with(&t, data.clone(), behavior).await;
+    // as it would be given to --peer
+    let peer = parse_peer(&format!("[::1]:{}", addr.port())).unwrap();
+    assert_eq!(peer, addr);
+    let (downloaded, _) = t
+        .download_all(&options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+}
+
+#[tokio::test]
+async fn same_download_with_one_or_many_verify_threads() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(8 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+
+    for verify_threads in [1, 8] {
+        let peer = mock_peer(&t, data.clone()).await;
+        let options = download::Options {
+            verify_threads,
+            ..options(peer)
+        };
+        let (downloaded, stats) = t
+            .download_all(&options, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+        assert_eq!(stats.bytes, data.len());
+        assert!(downloaded.verify_all(&t).is_ok());
+
+        // and with corrupt copies to turn down along the way
+        let corrupt = Behavior {
+            corrupt: true,
+            ip: Some(Ipv4Addr::new(127, 0, 0, 2).into()),
+            ..Default::default()
+        };
+        let corrupt = mock_peer_with(&t, data.clone(), corrupt).await;
+        let late = Behavior {
+            unchoke_after: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let late = mock_peer_with(&t, data.clone(), late).await;
+        let options = download::Options {
+            peers: vec![corrupt, late],
+            ..options
+        };
+        let (downloaded, stats) = t
+            .download_all(&options, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+        assert!(!stats.retried_pieces.is_empty());
+        assert_eq!(stats.bytes_per_peer.keys().collect::<Vec<_>>(), [&late]);
+    }
+}
+
+#[tokio::test]
+async fn downloads_in_small_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let options = download::Options {
+        block_size: 3000,
+        ..options(peer)
+    };
+    let (downloaded, _) = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+}
+
+#[tokio::test]
+async fn accepts_bitfield_after_extended_handshake() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+
+    let behavior = Behavior {
+        extended_first: true,
+        ..Default::default()
+    };
+    let peer = mock_peer_with(&t, data.clone(), behavior).await;
+    let (downloaded, _) = t
+        .download_all(&options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+}
+
+#[tokio::test]
+async fn keeps_to_the_peers_reqq() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(4 * PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    // (the reqq it sends, our block size, the most requests we should have outstanding); without
+    // a reqq there have to be more blocks to a piece than the default
+    for (reqq, block_size, expected) in [(Some(3), 3000, 3), (None, 32, 250)] {
+        let behavior = Behavior {
+            reqq,
+            hold_requests: true,
+            ..Default::default()
+        };
+        let max_outstanding = behavior.max_outstanding.clone();
+        let peer = mock_peer_with(&t, data.clone(), behavior).await;
+        let options = download::Options {
+            block_size,
+            pipeline_depth: 1000,
+            ..options(peer)
+        };
+        let (downloaded, _) = t
+            .download_all(&options, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+        assert_eq!(
+            max_outstanding.load(Ordering::Relaxed),
+            expected,
+            "{reqq:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn waits_out_a_trickled_handshake() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+
+    let behavior = Behavior {
+        trickle_handshake: true,
+        ..Default::default()
+    };
+    let peer = mock_peer_with(&t, data.clone(), behavior).await;
+    let (downloaded, _) = t
+        .download_all(&options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+}
+
+/// Writes `data` to `path`, except for piece `corrupt`, which is zeroed.
+fn write_corrupted(path: &std::path::Path, data: &[u8], corrupt: usize) {
+    let mut on_disk = data.to_vec();
+    on_disk[corrupt * PIECE_LENGTH..][..PIECE_LENGTH].fill(0);
+    std::fs::write(path, on_disk).unwrap();
+}
+
+#[tokio::test]
+async fn downloads_every_file_of_a_multi_file_torrent() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 4500);
+    // the middle file starts and ends in the middle of pieces
+    let files = [
+        ("a.bin", 1000),
+        ("b.bin", PIECE_LENGTH + 500),
+        ("c.bin", PIECE_LENGTH + 3000),
+    ];
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    let mut offset = 0;
+    for (name, length) in files {
+        std::fs::write(root.join(name), &data[offset..offset + length]).unwrap();
+        offset += length;
+    }
+    let t = Torrent::create(&root, String::new(), Some(PIECE_LENGTH)).unwrap();
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let (downloaded, _) = t
+        .download_all(&options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    let mut offset = 0;
+    let mut downloaded = downloaded.iter_files();
+    for (name, length) in files {
+        let (path, bytes) = downloaded.next().unwrap();
+        assert_eq!(path, std::path::Path::new(name));
+        assert_eq!(bytes, &data[offset..offset + length]);
+        offset += length;
+    }
+    assert!(downloaded.next().is_none());
+
+    // and the same files on disk, under a directory named after the torrent
+    let peer = mock_peer(&t, data.clone()).await;
+    let output = dir.path().join("out");
+    let mut storage = FileStorage::create(&t.info, &output).unwrap();
+    t.download_into(&mut storage, &options(peer), CancellationToken::new())
+        .await
+        .unwrap();
+    let mut offset = 0;
+    for (name, length) in files {
+        let written = std::fs::read(output.join("root").join(name)).unwrap();
+        assert_eq!(written, &data[offset..offset + length]);
+        offset += length;
+    }
+}
+
+#[tokio::test]
+async fn resume_redownloads_corrupt_piece() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    let output = dir.path().join("out.bin");
+    write_corrupted(&output, &data, 1);
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let options = download::Options {
+        resume: true,
+        ..options(peer)
+    };
+    let mut storage = FileStorage::open(&t.info, &output).unwrap();
+    let stats = t
+        .download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(stats.bytes, PIECE_LENGTH);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[tokio::test]
+async fn downloads_only_the_selected_pieces() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(3 * PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+    let output = dir.path().join("out.bin");
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let options = download::Options {
+        pieces: Some(vec![1]),
+        ..options(peer)
+    };
+    let mut storage = FileStorage::create(&t.info, &output).unwrap();
+    let stats = t
+        .download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(stats.bytes, PIECE_LENGTH);
+    let written = std::fs::read(&output).unwrap();
+    assert_eq!(
+        &written[PIECE_LENGTH..2 * PIECE_LENGTH],
+        &data[PIECE_LENGTH..2 * PIECE_LENGTH]
+    );
+
+    // a piece past the end is refused before we contact anyone
+    let out_of_range = download::Options {
+        pieces: Some(vec![1, 3]),
+        ..options.clone()
+    };
+    let e = t
+        .download_into(&mut storage, &out_of_range, CancellationToken::new())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "piece 3 is out of range; the torrent has 3 pieces"
+    );
+}
+
+#[tokio::test]
+async fn fast_resume_trusts_the_record_until_the_files_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    let output = dir.path().join("out.bin");
+    let resume_file = dir.path().join("out.bin.resume");
+    let storage = |create: bool| {
+        let storage = if create {
+            FileStorage::create(&t.info, &output).unwrap()
+        } else {
+            FileStorage::open(&t.info, &output).unwrap()
+        };
+        storage.with_resume_file(resume_file.clone(), t.info_hash())
+    };
+    // an interrupted download that got the first two pieces
+    let mut interrupted = storage(true);
+    interrupted.write_piece(0, &data[..PIECE_LENGTH]).unwrap();
+    interrupted
+        .write_piece(1, &data[PIECE_LENGTH..2 * PIECE_LENGTH])
+        .unwrap();
+    interrupted.record_completed(&[0, 1]).unwrap();
+    assert_eq!(storage(false).completed(), Some(vec![0, 1]));
+
+    // the record is only as good as the files: piece 0 goes bad behind its back
+    let mut on_disk = std::fs::read(&output).unwrap();
+    on_disk[..PIECE_LENGTH].fill(0);
+    std::fs::write(&output, on_disk).unwrap();
+    // file times can be too coarse to tell two writes in quick succession apart
+    std::fs::File::options()
+        .write(true)
+        .open(&output)
+        .unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+    assert_eq!(storage(false).completed(), None);
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let options = download::Options {
+        fast_resume: true,
+        ..options(peer)
+    };
+    let stats = t
+        .download_into(&mut storage(false), &options, CancellationToken::new())
+        .await
+        .unwrap();
+    // every piece was hashed, so only piece 1 was kept
+    assert_eq!(stats.bytes, PIECE_LENGTH + 1000);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    // and there's nothing left to resume
+    assert!(!resume_file.exists());
+}
+
+#[tokio::test]
+async fn fast_resume_downloads_only_what_the_record_lacks() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    let output = dir.path().join("out.bin");
+    let resume_file = dir.path().join("out.bin.resume");
+    // pieces 0 and 2 are complete; piece 1 is corrupt but the record says as much
+    write_corrupted(&output, &data, 1);
+    FileStorage::open(&t.info, &output)
+        .unwrap()
+        .with_resume_file(resume_file.clone(), t.info_hash())
+        .record_completed(&[0, 2])
+        .unwrap();
+
+    let peer = mock_peer(&t, data.clone()).await;
+    let options = download::Options {
+        fast_resume: true,
+        ..options(peer)
+    };
+    let mut storage = FileStorage::open(&t.info, &output)
+        .unwrap()
+        .with_resume_file(resume_file, t.info_hash());
+    let stats = t
+        .download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(stats.bytes, PIECE_LENGTH);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[tokio::test]
+async fn complete_resume_contacts_nobody() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(2 * PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    let output = dir.path().join("out.bin");
+    std::fs::write(&output, &data).unwrap();
+
+    // a peer that would notice if we connected
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let options = download::Options {
+        resume: true,
+        ..options(listener.local_addr().unwrap())
+    };
+    let mut storage = FileStorage::open(&t.info, &output).unwrap();
+    let stats = t
+        .download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(stats.bytes, 0);
+    assert_eq!(stats.peers_used(), 0);
+    let connected =
+        tokio::time::timeout(std::time::Duration::from_millis(100), listener.accept()).await;
+    assert!(connected.is_err(), "we connected to a peer");
+}
+
+#[tokio::test]
+async fn download_piece_rejects_a_mislabelled_block() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH + 1000);
+    let t = torrent_for(&dir, &data);
+    let torrent = dir.path().join("t.torrent");
+    std::fs::write(&torrent, t.to_bytes().unwrap()).unwrap();
+
+    let behavior = Behavior {
+        mislabel_blocks: true,
+        ..Default::default()
+    };
+    let peer = mock_peer_with(&t, data, behavior).await;
+    let output = dir.path().join("piece");
+    let run = tokio::process::Command::new(env!("CARGO_BIN_EXE_bittorrent-starter-rust"))
+        .arg("download_piece")
+        .arg("-o")
+        .arg(&output)
+        .arg("--peer")
+        .arg(peer.to_string())
+        .arg(&torrent)
+        .arg("0")
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .await
+        .unwrap();
+    assert!(!run.status.success());
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(
+        stderr.contains(
+            "peer sent the block at 1 of piece 0, but we asked for the one at 0 of piece 0"
+        ),
+        "{stderr}"
+    );
+    assert!(!output.exists());
+}
+
+#[tokio::test]
+async fn end_game_stores_a_piece_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    // the first peer takes the only piece and sits on it, so once the second one unchokes us it
+    // gets a copy of the same piece
+    let silent = Behavior {
+        silent: true,
+        ..Default::default()
+    };
+    let cancels = silent.cancels.clone();
+    let silent = mock_peer_with(&t, data.clone(), silent).await;
+    let late = Behavior {
+        unchoke_after: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let late = mock_peer_with(&t, data.clone(), late).await;
+    let options = download::Options {
+        peers: vec![silent, late],
+        ..options(silent)
+    };
+    let output = dir.path().join("out.bin");
+    let mut storage = Recording {
+        inner: FileStorage::create(&t.info, &output).unwrap(),
+        written: Vec::new(),
+    };
+    let stats = t
+        .download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(storage.written, [0]);
+    assert_eq!(stats.bytes, PIECE_LENGTH);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    // the silent peer is told it needn't bother any more
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while cancels.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no cancel for the losing copy");
+}
+
+#[tokio::test]
+async fn poisoned_swarm_gives_up_on_the_piece() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+    let corrupt = |ip| Behavior {
+        corrupt: true,
+        ip: Some(ip),
+        ..Default::default()
+    };
+    let download = |peers: Vec<SocketAddr>| {
+        let options = download::Options {
+            peers: peers.clone(),
+            ..options(peers[0])
+        };
+        let t = t.clone();
+        async move {
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                t.download_all(&options, CancellationToken::new()),
+            )
+            .await
+            .expect("download never gave up")
+            .err()
+            .expect("download of a corrupt piece succeeded")
+        }
+    };
+
+    // between them they send more corrupt copies than we try, but neither on its own does
+    let first = mock_peer_with(
+        &t,
+        data.clone(),
+        corrupt(Ipv4Addr::new(127, 0, 0, 2).into()),
+    )
+    .await;
+    let second = mock_peer_with(
+        &t,
+        data.clone(),
+        corrupt(Ipv4Addr::new(127, 0, 0, 3).into()),
+    )
+    .await;
+    let e = download(vec![first, second]).await;
+    assert!(
+        matches!(
+            e,
+            bittorrent_starter_rust::Error::PieceHashMismatch { piece: 0 }
+        ),
+        "{e:?}"
+    );
+
+    // banning one connection from a host bans them all, rather than going on with the other
+    let first = mock_peer_with(&t, data.clone(), corrupt(Ipv4Addr::LOCALHOST.into())).await;
+    let second = mock_peer_with(&t, data.clone(), corrupt(Ipv4Addr::LOCALHOST.into())).await;
+    download(vec![first, second]).await;
+}
+
+#[tokio::test]
+async fn corrupt_peer_is_banned_and_its_piece_retried() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(4 * PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    // peers are banned by IP, so the bad one mustn't share the good one's
+    let corrupt = Behavior {
+        corrupt: true,
+        ip: Some(Ipv4Addr::new(127, 0, 0, 2).into()),
+        ..Default::default()
+    };
+    let corrupt = mock_peer_with(&t, data.clone(), corrupt).await;
+    // until it unchokes us, the corrupt peer is all we have; if it weren't banned, it would use up
+    // the attempts at a piece long before then
+    let good = Behavior {
+        unchoke_after: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let good = mock_peer_with(&t, data.clone(), good).await;
+    let options = download::Options {
+        peers: vec![corrupt, good],
+        ..options(corrupt)
+    };
+    let (downloaded, stats) = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+    assert!(!stats.retried_pieces.is_empty());
+    assert_eq!(
+        stats.bytes_per_peer.keys().collect::<Vec<_>>(),
+        [&good],
+        "{stats:?}"
+    );
+}
+
+#[tokio::test]
+async fn silent_peer_loses_its_piece_to_another() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    let silent = Behavior {
+        silent: true,
+        ..Default::default()
+    };
+    let silent = mock_peer_with(&t, data.clone(), silent).await;
+    // the other peer only unchokes us once the silent one has timed out, so the piece can't have
+    // reached it through the end game
+    let late = Behavior {
+        unchoke_after: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let late = mock_peer_with(&t, data.clone(), late).await;
+    let options = download::Options {
+        peers: vec![silent, late],
+        block_timeout: Duration::from_millis(100),
+        ..options(silent)
+    };
+    let (downloaded, stats) = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+    assert!(stats.retried_pieces.contains(&0));
+    assert_eq!(
+        stats.bytes_per_peer.keys().collect::<Vec<_>>(),
+        [&late],
+        "{stats:?}"
+    );
+}
+
+#[tokio::test]
+async fn piece_moves_on_when_a_peer_hangs_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    let quitter = Behavior {
+        hang_up_on_request: true,
+        ..Default::default()
+    };
+    let quitter = mock_peer_with(&t, data.clone(), quitter).await;
+    let late = Behavior {
+        unchoke_after: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let late = mock_peer_with(&t, data.clone(), late).await;
+    let options = download::Options {
+        peers: vec![quitter, late],
+        ..options(quitter)
+    };
+    let (downloaded, stats) = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+    // handed back by the peer that hung up, rather than lost with it
+    assert!(stats.retried_pieces.contains(&0));
+    assert_eq!(
+        stats.bytes_per_peer.keys().collect::<Vec<_>>(),
+        [&late],
+        "{stats:?}"
+    );
+}
+
+#[tokio::test]
+async fn peer_sending_oversized_blocks_is_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    // 32 KiB in answer to our 16 KiB request
+    let oversized = Behavior {
+        double_blocks: true,
+        ..Default::default()
+    };
+    let oversized = mock_peer_with(&t, data.clone(), oversized).await;
+    let late = Behavior {
+        unchoke_after: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let late = mock_peer_with(&t, data.clone(), late).await;
+    let options = download::Options {
+        peers: vec![oversized, late],
+        ..options(oversized)
+    };
+    let (downloaded, stats) = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(downloaded.into_iter().next().unwrap().bytes(), data);
+    assert!(stats.retried_pieces.contains(&0));
+    assert_eq!(
+        stats.bytes_per_peer.keys().collect::<Vec<_>>(),
+        [&late],
+        "{stats:?}"
+    );
+}
+
+// the storage blocks whichever thread writes to it, so the mock peer needs one of its own
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn slow_storage_holds_up_downloading() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = content(30 * PIECE_LENGTH);
+    let t = torrent_for(&dir, &data);
+
+    let behavior = Behavior::default();
+    let sent = behavior.sent.clone();
+    let peer = mock_peer_with(&t, data.clone(), behavior).await;
+    let options = download::Options {
+        max_peers: 1,
+        verify_threads: 1,
+        ..options(peer)
+    };
+    let output = dir.path().join("out.bin");
+    let mut storage = Slow {
+        inner: FileStorage::create(&t.info, &output).unwrap(),
+        sent,
+        written: 0,
+        max_unwritten: 0,
+    };
+    t.download_into(&mut storage, &options, CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    // the piece being written, one being hashed, two reports waiting in the channel, and the one
+    // the peer is holding on to until there's room for its report
+    assert!(storage.max_unwritten <= 5, "{}", storage.max_unwritten);
+}
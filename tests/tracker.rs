@@ -0,0 +1,290 @@
+//! Announces against a mock HTTP tracker on loopback.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{
+    download,
+    tracker::{self, TrackerRequest},
+    Torrent, TrackerResponse,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+/// `d8:intervali900e5:peers12:<127.0.0.1:6881><10.0.0.2:6882>e`, gzipped.
+const GZIPPED_RESPONSE: [u8; 59] = [
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xb1, 0xb0, 0xca, 0xcc, 0x2b,
+    0x49, 0x2d, 0x2a, 0x4b, 0xcc, 0xc9, 0xb4, 0x34, 0x30, 0x48, 0x35, 0xb5, 0x2a, 0x48, 0x4d, 0x2d,
+    0x2a, 0x36, 0x34, 0xb2, 0xaa, 0x67, 0x60, 0x60, 0x94, 0x7a, 0xc8, 0xc5, 0xc0, 0xc0, 0x24, 0xf5,
+    0x28, 0x15, 0x00, 0xe7, 0x92, 0x1f, 0x20, 0x27, 0x00, 0x00, 0x00,
+];
+
+/// How [`mock_tracker_with`] should misbehave.
+#[derive(Debug, Clone, Default)]
+struct Behavior {
+    /// How many announces to hang up on before answering any.
+    failures: usize,
+    /// How long to wait before answering each announce.
+    delay: Duration,
+    /// The most announces that have been in flight at once.
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+/// Starts a tracker that answers every announce with `headers` and `body`, returning its
+/// announce URL and the requests it gets. It hangs up on any request without our user agent.
+async fn mock_tracker(
+    headers: &'static str,
+    body: &'static [u8],
+) -> (String, mpsc::UnboundedReceiver<String>) {
+    mock_tracker_with(headers, body, Behavior::default()).await
+}
+
+/// Like [`mock_tracker`], but misbehaving as `behavior` says.
+async fn mock_tracker_with(
+    headers: &'static str,
+    body: &'static [u8],
+    behavior: Behavior,
+) -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        for announce in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (tx, behavior, in_flight) = (tx.clone(), behavior.clone(), in_flight.clone());
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                let request = String::from_utf8(request).unwrap();
+                // every request says who it's from (headers are case-insensitive)
+                assert!(
+                    request
+                        .to_ascii_lowercase()
+                        .contains("\r\nuser-agent: your_bittorrent/0.1\r\n"),
+                    "{request}"
+                );
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                behavior.max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(behavior.delay).await;
+                if announce >= behavior.failures {
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.write_all(body).await.unwrap();
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let _ = tx.send(request);
+            });
+        }
+    });
+    (format!("http://{addr}/announce"), rx)
+}
+
+fn torrent(dir: &tempfile::TempDir, announce: String) -> Torrent {
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, [0; 1000]).unwrap();
+    Torrent::create(&path, announce, None).unwrap()
+}
+
+#[tokio::test]
+async fn gzipped_response_is_decompressed() {
+    let (announce, mut requests) =
+        mock_tracker("Content-Encoding: gzip\r\n", &GZIPPED_RESPONSE).await;
+    let dir = tempfile::tempdir().unwrap();
+    let t = torrent(&dir, announce);
+
+    let client = tracker::client(None).unwrap();
+    let response =
+        TrackerResponse::query(&client, &t, t.info_hash(), &TrackerRequest::new(&t, 6881))
+            .await
+            .unwrap();
+    let peers: Vec<_> = response.peer_addrs().collect();
+    assert_eq!(
+        peers,
+        [
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap()
+        ]
+    );
+    assert_eq!(response.interval, 900);
+
+    let request = requests.recv().await.unwrap().to_ascii_lowercase();
+    assert!(request.contains("accept-encoding: gzip"), "{request}");
+}
+
+#[tokio::test]
+async fn plain_response_still_parses() {
+    let (announce, _) =
+        mock_tracker("", b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+    let dir = tempfile::tempdir().unwrap();
+    let t = torrent(&dir, announce);
+
+    let client = tracker::client(None).unwrap();
+    let response =
+        TrackerResponse::query(&client, &t, t.info_hash(), &TrackerRequest::new(&t, 6881))
+            .await
+            .unwrap();
+    assert_eq!(
+        response.peer_addrs().collect::<Vec<_>>(),
+        ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+    );
+}
+
+/// The value of query parameter `name` in an HTTP request.
+fn query_param<'r>(request: &'r str, name: &str) -> &'r str {
+    let (_, query) = request.split_once('?').unwrap();
+    let query = query.split_once(' ').unwrap().0;
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+        .unwrap_or_else(|| panic!("no {name} in {request}"))
+}
+
+#[tokio::test]
+async fn peer_id_and_key_are_stable_across_announces() {
+    let (announce, mut requests) = mock_tracker("", b"d8:intervali60e5:peers0:e").await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut t = torrent(&dir, announce);
+    // private trackers are the ones that insist on it
+    t.info.private = Some(1);
+
+    let client = tracker::client(None).unwrap();
+    let mut request = TrackerRequest::new(&t, 6881);
+    TrackerResponse::query(&client, &t, t.info_hash(), &request)
+        .await
+        .unwrap();
+    request.event = None;
+    request.downloaded = 1000;
+    TrackerResponse::query(&client, &t, t.info_hash(), &request)
+        .await
+        .unwrap();
+    // a fresh request, as for a later download in the same session
+    TrackerResponse::query(&client, &t, t.info_hash(), &TrackerRequest::new(&t, 6882))
+        .await
+        .unwrap();
+
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        let request = requests.recv().await.unwrap();
+        seen.push((
+            query_param(&request, "peer_id").to_string(),
+            query_param(&request, "key").to_string(),
+        ));
+    }
+    assert!(!seen[0].1.is_empty());
+    assert_eq!(seen[0], seen[1]);
+    assert_eq!(seen[0], seen[2]);
+}
+
+#[tokio::test]
+async fn no_peers_is_an_error() {
+    let (announce, _) = mock_tracker("", b"d8:intervali60e5:peers0:e").await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut t = torrent(&dir, announce);
+    // so the DHT isn't asked instead
+    t.info.private = Some(1);
+
+    let options = download::Options {
+        port: 0,
+        ..Default::default()
+    };
+    let e = t
+        .download_all(&options, CancellationToken::new())
+        .await
+        .err()
+        .expect("download with no peers succeeded");
+    assert_eq!(e.to_string(), "tracker returned no peers");
+}
+
+#[tokio::test]
+async fn announce_is_retried_after_failures() {
+    let behavior = Behavior {
+        failures: 2,
+        ..Default::default()
+    };
+    let (announce, mut requests) = mock_tracker_with(
+        "",
+        b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+        behavior,
+    )
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    let t = torrent(&dir, announce);
+
+    let client = tracker::client(None).unwrap();
+    let response =
+        TrackerResponse::query(&client, &t, t.info_hash(), &TrackerRequest::new(&t, 6881))
+            .await
+            .unwrap();
+    assert_eq!(
+        response.peer_addrs().collect::<Vec<_>>(),
+        ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+    );
+    for _ in 0..3 {
+        requests.recv().await.unwrap();
+    }
+    assert!(requests.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn concurrent_announces_take_turns() {
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let behavior = Behavior {
+        delay: Duration::from_millis(100),
+        max_in_flight: max_in_flight.clone(),
+        ..Default::default()
+    };
+    let (announce, _) = mock_tracker_with("", b"d8:intervali60e5:peers0:e", behavior).await;
+    let dir = tempfile::tempdir().unwrap();
+    let t = torrent(&dir, announce);
+
+    let client = tracker::client(None).unwrap();
+    let announces = (0..4).map(|i| {
+        let (client, t) = (client.clone(), t.clone());
+        tokio::spawn(async move {
+            let request = TrackerRequest::new(&t, 6881 + i);
+            TrackerResponse::query(&client, &t, t.info_hash(), &request).await
+        })
+    });
+    for announce in futures_util::future::join_all(announces).await {
+        announce.unwrap().unwrap();
+    }
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn announces_go_through_the_proxy() {
+    // the mock tracker answers whatever it's asked, as a proxy
+    let (proxy, mut requests) =
+        mock_tracker("", b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+    let proxy = proxy.trim_end_matches("/announce");
+    let dir = tempfile::tempdir().unwrap();
+    let t = torrent(&dir, "http://tracker.invalid/announce".to_string());
+
+    let client = tracker::client(Some(proxy)).unwrap();
+    let response =
+        TrackerResponse::query(&client, &t, t.info_hash(), &TrackerRequest::new(&t, 6881))
+            .await
+            .unwrap();
+    assert_eq!(response.peer_addrs().count(), 1);
+    let request = requests.recv().await.unwrap();
+    assert!(
+        request.starts_with("GET http://tracker.invalid/announce?"),
+        "{request}"
+    );
+}